@@ -0,0 +1,100 @@
+use crate::net::IpNet;
+use std::{fs::read_to_string, net::IpAddr, path::Path, process::Command};
+
+/// IP addresses and networks which are never banned, loopback is always included
+#[derive(Default)]
+pub struct Allowlist {
+    nets: Vec<IpNet>,
+}
+
+impl Allowlist {
+    /// Parses addresses and networks separated by commas, whitespace or newlines,
+    /// everything after `#` on a line is a comment
+    pub fn parse(list: &str) -> Result<Self, String> {
+        let mut allowlist = Self::default();
+        allowlist.extend(list)?;
+        Ok(allowlist)
+    }
+
+    pub fn extend(&mut self, list: &str) -> Result<(), String> {
+        for line in list.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+
+            for entry in line.split([',', ' ', '\t']).filter(|e| !e.is_empty()) {
+                self.nets.push(entry.parse()?);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn load_file(&mut self, path: &Path) -> Result<(), String> {
+        let list =
+            read_to_string(path).map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+        self.extend(&list)
+            .map_err(|e| format!("Invalid allowlist {}: {e}", path.display()))
+    }
+
+    /// Adds the addresses of this host as reported by `hostname -I`
+    pub fn add_local_addresses(&mut self) -> Result<(), String> {
+        let output = Command::new("hostname")
+            .arg("-I")
+            .output()
+            .map_err(|e| format!("Failed to run hostname: {e}"))?;
+
+        let addresses = String::from_utf8_lossy(&output.stdout);
+        self.nets.extend(
+            addresses
+                .split_whitespace()
+                .filter_map(|a| a.parse::<IpAddr>().ok())
+                .map(IpNet::host),
+        );
+
+        Ok(())
+    }
+
+    /// Whether any allowlisted address is part of the network, loopback included
+    pub fn overlaps(&self, net: &IpNet) -> bool {
+        let loopback = ["127.0.0.0/8", "::1"].map(|l| l.parse::<IpNet>().unwrap());
+        self.nets.iter().chain(&loopback).any(|n| n.overlaps(net))
+    }
+
+    pub fn len(&self) -> usize {
+        self.nets.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Allowlist;
+    use crate::net::IpNet;
+
+    fn host(s: &str) -> IpNet {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let allowlist = Allowlist::parse(
+            "203.0.113.7, 10.0.0.0/8\n\
+            # Office\n\
+            2001:db8:100::/48 # IPv6 office range\n",
+        )
+        .unwrap();
+
+        assert_eq!(allowlist.len(), 3);
+        assert!(allowlist.overlaps(&host("203.0.113.7")));
+        assert!(allowlist.overlaps(&host("10.20.30.40")));
+        assert!(allowlist.overlaps(&host("2001:db8:100:5::1")));
+        assert!(allowlist.overlaps(&host("127.0.0.1")));
+        assert!(allowlist.overlaps(&host("::1")));
+        assert!(!allowlist.overlaps(&host("203.0.113.8")));
+        assert!(!allowlist.overlaps(&host("2001:db8:101::1")));
+
+        assert!(allowlist.overlaps(&"203.0.113.0/24".parse().unwrap()));
+        assert!(allowlist.overlaps(&"127.0.0.0/24".parse().unwrap()));
+        assert!(!allowlist.overlaps(&"203.0.114.0/24".parse().unwrap()));
+
+        assert!(Allowlist::parse("10.0.0.0/8, office").is_err());
+    }
+}
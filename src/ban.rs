@@ -0,0 +1,174 @@
+use crate::net::IpNet;
+use std::{fmt, str::FromStr};
+
+// iptables multiport accepts at most 15 ports
+const MAX_PORTS: usize = 15;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Proto {
+    Tcp,
+    Udp,
+}
+
+impl Proto {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Tcp => "tcp",
+            Self::Udp => "udp",
+        }
+    }
+}
+
+impl FromStr for Proto {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "tcp" | "6" => Ok(Self::Tcp),
+            "udp" | "17" => Ok(Self::Udp),
+            other => Err(format!("Unknown protocol '{other}'")),
+        }
+    }
+}
+
+/// Destination ports a ban is limited to, e.g. `80,443/tcp`
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PortScope {
+    pub proto: Proto,
+    pub ports: Vec<u16>,
+}
+
+impl PortScope {
+    pub fn new(proto: Proto, mut ports: Vec<u16>) -> Self {
+        ports.sort_unstable();
+        ports.dedup();
+        Self { proto, ports }
+    }
+
+    /// Comma-separated ports, as used by iptables multiport
+    pub fn list(&self) -> String {
+        let ports: Vec<String> = self.ports.iter().map(u16::to_string).collect();
+        ports.join(",")
+    }
+}
+
+impl fmt::Display for PortScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.list(), self.proto.as_str())
+    }
+}
+
+impl FromStr for PortScope {
+    type Err = String;
+
+    // 22/tcp, 80,443/tcp or 53/udp; the protocol defaults to tcp
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ports, proto) = match s.trim().split_once('/') {
+            Some((ports, proto)) => (ports, proto.parse()?),
+            None => (s.trim(), Proto::Tcp),
+        };
+
+        let ports = ports
+            .split(',')
+            .map(|port| {
+                port.trim()
+                    .parse::<u16>()
+                    .map_err(|_| format!("Invalid port '{port}'"))
+            })
+            .collect::<Result<Vec<u16>, String>>()?;
+
+        if ports.len() > MAX_PORTS {
+            return Err(format!("At most {MAX_PORTS} ports are supported"));
+        }
+
+        Ok(Self::new(proto, ports))
+    }
+}
+
+/// Banned IP address or network, optionally limited to some destination ports
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Ban {
+    pub net: IpNet,
+    pub ports: Option<PortScope>,
+}
+
+impl Ban {
+    pub fn new(net: IpNet, ports: Option<PortScope>) -> Self {
+        Self { net, ports }
+    }
+}
+
+impl fmt::Display for Ban {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.ports {
+            Some(ports) => write!(f, "{}@{ports}", self.net),
+            None => write!(f, "{}", self.net),
+        }
+    }
+}
+
+impl FromStr for Ban {
+    type Err = String;
+
+    // 1.2.3.4, 1.2.3.0/24 or 1.2.3.4@80,443/tcp
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (net, ports) = match s.trim().split_once('@') {
+            Some((net, ports)) => (net, Some(ports.parse()?)),
+            None => (s.trim(), None),
+        };
+
+        Ok(Self {
+            net: net.parse()?,
+            ports,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Ban, PortScope, Proto};
+
+    #[test]
+    fn test_parse_ban() {
+        let cases = [
+            ("1.2.3.4", "1.2.3.4", None),
+            (
+                "1.2.3.4@443,80/tcp",
+                "1.2.3.4@80,443/tcp",
+                Some(PortScope::new(Proto::Tcp, vec![80, 443])),
+            ),
+            (
+                "2001:db8::1@22",
+                "2001:db8::1@22/tcp",
+                Some(PortScope::new(Proto::Tcp, vec![22])),
+            ),
+            ("1.2.3.4/24", "1.2.3.0/24", None),
+            (
+                "2001:db8::/64@22/tcp",
+                "2001:db8::/64@22/tcp",
+                Some(PortScope::new(Proto::Tcp, vec![22])),
+            ),
+            (
+                "2001:db8::1@53/udp",
+                "2001:db8::1@53/udp",
+                Some(PortScope::new(Proto::Udp, vec![53])),
+            ),
+        ];
+
+        for (s, display, ports) in cases {
+            let ban: Ban = s.parse().unwrap();
+            assert_eq!(ban.ports, ports, "Ban that failed: {s}");
+            assert_eq!(ban.to_string(), display, "Ban that failed: {s}");
+        }
+
+        for s in [
+            "",
+            "localhost",
+            "1.2.3.4@",
+            "1.2.3.4@http",
+            "1.2.3.4@22/icmp",
+        ] {
+            assert!(s.parse::<Ban>().is_err(), "Should not parse {s}");
+        }
+    }
+}
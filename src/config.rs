@@ -0,0 +1,702 @@
+use crate::{
+    LogKind,
+    allowlist::Allowlist,
+    ban::PortScope,
+    firewall::{BanAction, FirewallBackend},
+    guard::{Policy, Recidive, Settings, Subnet},
+    test_path::{Field, Rule},
+};
+use std::{
+    fmt::Display,
+    fs::read_to_string,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+use toml::{Table, Value};
+
+pub const DEFAULT_PATH: &str = "/etc/ubuntu-guard/config.toml";
+
+const SECTIONS: [&str; 6] = [
+    "global",
+    "sources",
+    "firewall",
+    "allowlist",
+    "rules",
+    "output",
+];
+
+/// Configuration from the TOML file, every value can be overridden by its env var
+pub struct Config {
+    pub settings: Settings,
+    pub sources: Vec<SourceConfig>,
+    pub backend: FirewallBackend,
+    pub action: BanAction,
+    pub dry_run: bool,
+    pub allowlist: Allowlist,
+    pub allow_local_addresses: bool,
+    /// HTTP rules file, reloaded when it changes. None uses the built-in rules
+    pub rules_path: Option<PathBuf>,
+    /// Whether the rules of the file are added to the built-in ones instead of replacing them
+    pub default_rules: bool,
+}
+
+/// Enabled log source, read with the parser of its kind
+pub struct SourceConfig {
+    pub kind: LogKind,
+    /// Absolute file paths, `*` and `?` match within a path component
+    pub paths: Vec<String>,
+    /// None when bans block every port
+    pub ports: Option<PortScope>,
+    /// Global thresholds unless the source has its own
+    pub policy: Policy,
+    /// Paths which are never bad for this source, besides the ignore rules of the rules file
+    pub ignores: Vec<Rule>,
+}
+
+// Table of the configuration file, a missing table only has defaults
+struct Section<'a> {
+    name: String,
+    table: Option<&'a Table>,
+    env: &'a dyn Fn(&str) -> Option<String>,
+}
+
+impl<'a> Section<'a> {
+    fn new(
+        parent: Option<&'a Table>,
+        name: &str,
+        env: &'a dyn Fn(&str) -> Option<String>,
+    ) -> Result<Self, String> {
+        Self::with_name(parent, name, name.to_string(), env)
+    }
+
+    /// Subtable, a key with dots is quoted in messages like `[sources."example.com"]`
+    fn child(&self, key: &str) -> Result<Self, String> {
+        let name = match key.contains('.') {
+            true => format!("{}.\"{key}\"", self.name),
+            false => format!("{}.{key}", self.name),
+        };
+        Self::with_name(self.table, key, name, self.env)
+    }
+
+    fn with_name(
+        parent: Option<&'a Table>,
+        key: &str,
+        name: String,
+        env: &'a dyn Fn(&str) -> Option<String>,
+    ) -> Result<Self, String> {
+        let table = match parent.and_then(|parent| parent.get(key)) {
+            Some(Value::Table(table)) => Some(table),
+            Some(_) => return Err(format!("[{name}] must be a table")),
+            None => None,
+        };
+
+        Ok(Self { name, table, env })
+    }
+
+    fn check_keys(&self, keys: &[&str]) -> Result<(), String> {
+        match self
+            .table
+            .into_iter()
+            .flat_map(|table| table.keys())
+            .find(|key| !keys.contains(&key.as_str()))
+        {
+            Some(key) => Err(format!("[{}] unknown key '{key}'", self.name)),
+            None => Ok(()),
+        }
+    }
+
+    /// Value of the env var if set, then of the key, then the default
+    fn get<T>(&self, key: &str, env_name: Option<&str>, default: T) -> Result<T, String>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        if let Some(name) = env_name
+            && let Some(value) = (self.env)(name)
+        {
+            return value
+                .parse()
+                .map_err(|e| format!("{name}: invalid value '{value}': {e}"));
+        }
+
+        let Some(value) = self.table.and_then(|table| table.get(key)) else {
+            return Ok(default);
+        };
+
+        // Arrays are joined with commas, e.g. for allowlist entries
+        let value = match value {
+            Value::Array(items) => items
+                .iter()
+                .map(|item| self.scalar(key, item))
+                .collect::<Result<Vec<String>, String>>()?
+                .join(","),
+            value => self.scalar(key, value)?,
+        };
+
+        value
+            .parse()
+            .map_err(|e| format!("[{}] {key}: invalid value '{value}': {e}", self.name))
+    }
+
+    fn scalar(&self, key: &str, value: &Value) -> Result<String, String> {
+        match value {
+            Value::String(s) => Ok(s.clone()),
+            Value::Integer(i) => Ok(i.to_string()),
+            Value::Float(f) => Ok(f.to_string()),
+            Value::Boolean(b) => Ok(b.to_string()),
+            other => Err(format!(
+                "[{}] {key}: unsupported {} value",
+                self.name,
+                other.type_str()
+            )),
+        }
+    }
+
+    // String or array of strings
+    fn list(&self, key: &str, default: Vec<String>) -> Result<Vec<String>, String> {
+        match self.table.and_then(|table| table.get(key)) {
+            None => Ok(default),
+            Some(Value::String(s)) => Ok(vec![s.clone()]),
+            Some(Value::Array(items)) => items
+                .iter()
+                .map(|item| match item {
+                    Value::String(s) => Ok(s.clone()),
+                    other => Err(format!(
+                        "[{}] {key}: expected strings, found {}",
+                        self.name,
+                        other.type_str()
+                    )),
+                })
+                .collect(),
+            Some(other) => Err(format!(
+                "[{}] {key}: expected strings, found {}",
+                self.name,
+                other.type_str()
+            )),
+        }
+    }
+
+    fn seconds(
+        &self,
+        key: &str,
+        env_name: Option<&str>,
+        default: Duration,
+    ) -> Result<Duration, String> {
+        self.get(key, env_name, default.as_secs())
+            .map(Duration::from_secs)
+    }
+}
+
+impl Config {
+    /// Loads the file, defaults are used if it does not exist
+    pub fn load(path: &Path, env: &dyn Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let text = match read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(format!("Failed to read {}: {e}", path.display())),
+        };
+
+        Self::parse(&text, env).map_err(|e| format!("{}: {e}", path.display()))
+    }
+
+    pub fn parse(text: &str, env: &dyn Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let root: Table = text.parse().map_err(|e| format!("{e}"))?;
+
+        if let Some(key) = root.keys().find(|key| !SECTIONS.contains(&key.as_str())) {
+            return Err(format!("Unknown section [{key}]"));
+        }
+
+        let global = Section::new(Some(&root), "global", env)?;
+        global.check_keys(&[
+            "threshold",
+            "window_seconds",
+            "block_duration_seconds",
+            "ipv6_prefix",
+            "recidive_factor",
+            "recidive_max_seconds",
+            "recidive_permanent_after",
+            "subnet_threshold",
+            "subnet_window_seconds",
+            "subnet_prefix_v4",
+            "subnet_prefix_v6",
+        ])?;
+
+        let recidive = Recidive {
+            factor: global.get("recidive_factor", Some("RECIDIVE_FACTOR"), 1.0)?,
+            max_duration: global.seconds(
+                "recidive_max_seconds",
+                Some("RECIDIVE_MAX_SECONDS"),
+                Duration::from_secs(u64::MAX),
+            )?,
+            permanent_after: global.get(
+                "recidive_permanent_after",
+                Some("RECIDIVE_PERMANENT_AFTER"),
+                0,
+            )?,
+        };
+
+        if recidive.factor.is_nan() || recidive.factor < 1.0 {
+            return Err(format!(
+                "[global] recidive_factor: '{}' must be at least 1",
+                recidive.factor
+            ));
+        }
+
+        let subnet = Subnet {
+            threshold: global.get("subnet_threshold", Some("SUBNET_THRESHOLD"), 0)?,
+            window: global.seconds(
+                "subnet_window_seconds",
+                Some("SUBNET_WINDOW_SECONDS"),
+                Duration::from_secs(3600),
+            )?,
+            prefix_v4: global.get("subnet_prefix_v4", Some("SUBNET_PREFIX_V4"), 24)?,
+            prefix_v6: global.get("subnet_prefix_v6", Some("SUBNET_PREFIX_V6"), 48)?,
+        };
+        if subnet.prefix_v4 > 32 || subnet.prefix_v6 > 128 {
+            return Err("[global] subnet prefixes must be at most 32 and 128".to_string());
+        }
+
+        let ipv6_prefix = global.get("ipv6_prefix", Some("IPV6_PREFIX"), 64)?;
+        if ipv6_prefix > 128 {
+            return Err("[global] ipv6_prefix must be at most 128".to_string());
+        }
+
+        let output = Section::new(Some(&root), "output", env)?;
+        output.check_keys(&["banned_ip_path", "history_path", "log_path"])?;
+
+        let banned_ip_path: PathBuf = output.get(
+            "banned_ip_path",
+            Some("GUARD_BANNED_IP_PATH"),
+            "/var/lib/ubuntu-guard/banned_ips.txt".into(),
+        )?;
+        let history_path = output.get(
+            "history_path",
+            Some("GUARD_BAN_HISTORY_PATH"),
+            banned_ip_path.with_file_name("ban_history.txt"),
+        )?;
+        let log_path = output.get(
+            "log_path",
+            Some("GUARD_LOG_PATH"),
+            "/var/log/ubuntu-guard/ubuntu-guard.log".into(),
+        )?;
+
+        // Default for sources without their own policy
+        let default = Policy::default();
+        let policy = Policy {
+            threshold: global.get("threshold", Some("THRESHOLD"), default.threshold)?,
+            window: global.seconds("window_seconds", Some("WINDOW_SECONDS"), default.window)?,
+            block_duration: global.seconds(
+                "block_duration_seconds",
+                Some("BLOCK_DURATION_SECONDS"),
+                default.block_duration,
+            )?,
+        };
+
+        let settings = Settings {
+            recidive,
+            subnet,
+            ipv6_prefix,
+            banned_ip_path,
+            history_path,
+            log_path,
+        };
+
+        let firewall = Section::new(Some(&root), "firewall", env)?;
+        firewall.check_keys(&["backend", "action", "dry_run", "global_bans"])?;
+
+        // Bans only block the service of the log source unless global_bans is set
+        let global_bans = firewall.get("global_bans", Some("GLOBAL_BANS"), false)?;
+        let sources = Self::parse_sources(&root, env, global_bans, &policy)?;
+
+        let allowlist_section = Section::new(Some(&root), "allowlist", env)?;
+        allowlist_section.check_keys(&["entries", "path", "local_addresses"])?;
+
+        let mut allowlist = Allowlist::parse(&allowlist_section.get::<String>(
+            "entries",
+            Some("ALLOWLIST"),
+            String::new(),
+        )?)
+        .map_err(|e| format!("[allowlist] entries: {e}"))?;
+
+        let allowlist_path: String =
+            allowlist_section.get("path", Some("ALLOWLIST_PATH"), String::new())?;
+        if !allowlist_path.is_empty() {
+            allowlist.load_file(allowlist_path.as_ref())?;
+        }
+
+        let rules = Section::new(Some(&root), "rules", env)?;
+        rules.check_keys(&["path", "defaults"])?;
+        let rules_path: String = rules.get("path", Some("RULES_PATH"), String::new())?;
+        if !rules_path.is_empty() && !Path::new(&rules_path).is_absolute() {
+            return Err(format!("[rules] path: '{rules_path}' must be absolute"));
+        }
+
+        Ok(Self {
+            settings,
+            sources,
+            backend: firewall.get(
+                "backend",
+                Some("FIREWALL_BACKEND"),
+                FirewallBackend::Iptables,
+            )?,
+            action: firewall.get("action", Some("BAN_ACTION"), BanAction::default())?,
+            dry_run: firewall.get("dry_run", Some("DRY_RUN"), false)?,
+            allowlist,
+            allow_local_addresses: allowlist_section.get("local_addresses", None, true)?,
+            rules_path: (!rules_path.is_empty()).then(|| rules_path.into()),
+            default_rules: rules.get("defaults", None, true)?,
+        })
+    }
+
+    fn parse_sources(
+        root: &Table,
+        env: &dyn Fn(&str) -> Option<String>,
+        global_bans: bool,
+        policy: &Policy,
+    ) -> Result<Vec<SourceConfig>, String> {
+        let builtin = [
+            ("ssh", "/var/log/auth.log"),
+            ("apache", "/var/log/apache2/*access.log"),
+            ("nginx", "/var/log/nginx/*access.log"),
+        ];
+
+        let sources = Section::new(Some(root), "sources", env)?;
+
+        // Built-in sources are enabled unless disabled, then user-defined ones
+        let mut names: Vec<&str> = builtin.iter().map(|(name, _)| *name).collect();
+        for name in sources.table.into_iter().flat_map(|table| table.keys()) {
+            if !names.contains(&name.as_str()) {
+                names.push(name);
+            }
+        }
+
+        let mut configs = Vec::new();
+        for name in names {
+            let source = sources.child(name)?;
+            source.check_keys(&[
+                "enabled",
+                "kind",
+                "paths",
+                "ports",
+                "threshold",
+                "window_seconds",
+                "block_duration_seconds",
+                "ignore",
+            ])?;
+
+            if !source.get("enabled", None, true)? {
+                continue;
+            }
+
+            let kind: LogKind = match source.get("kind", None, String::new())?.as_str() {
+                "" => name
+                    .parse()
+                    .map_err(|_| format!("[{}] kind is required", source.name))?,
+                kind => kind
+                    .parse()
+                    .map_err(|e| format!("[{}] kind: {e}", source.name))?,
+            };
+
+            let default_paths = builtin
+                .iter()
+                .filter(|(builtin, _)| *builtin == name)
+                .map(|(_, path)| path.to_string())
+                .collect();
+            let paths = source.list("paths", default_paths)?;
+            if paths.is_empty() {
+                return Err(format!("[{}] paths is required", source.name));
+            }
+            if let Some(path) = paths.iter().find(|path| {
+                !Path::new(path).is_absolute() || Path::new(path).file_name().is_none()
+            }) {
+                return Err(format!(
+                    "[{}] paths: '{path}' must be an absolute file path",
+                    source.name
+                ));
+            }
+
+            // The env var only replaces the default, the ports of a source win
+            let (ports_env, ports) = match kind {
+                LogKind::Ssh => ("SSH_PORTS", "22/tcp"),
+                LogKind::Apache | LogKind::Nginx => ("HTTP_PORTS", "80,443/tcp"),
+            };
+            let ports: PortScope = match env(ports_env) {
+                Some(value) => value
+                    .parse()
+                    .map_err(|e| format!("{ports_env}: invalid value '{value}': {e}"))?,
+                None => ports.parse()?,
+            };
+            let ports = source.get("ports", None, ports)?;
+
+            let policy = Policy {
+                threshold: source.get("threshold", None, policy.threshold)?,
+                window: source.seconds("window_seconds", None, policy.window)?,
+                block_duration: source.seconds(
+                    "block_duration_seconds",
+                    None,
+                    policy.block_duration,
+                )?,
+            };
+
+            let ignores = source
+                .list("ignore", Vec::new())?
+                .iter()
+                .map(|rule| {
+                    Rule::parse_ignore(rule)
+                        .map_err(|e| format!("[{}] ignore: '{rule}': {e}", source.name))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            // HTTP sources have every field but the sshd message
+            let is_ssh = matches!(kind, LogKind::Ssh);
+            if let Some(rule) = ignores
+                .iter()
+                .find(|rule| rule.fields().any(|field| (field == Field::Ssh) != is_ssh))
+            {
+                return Err(format!(
+                    "[{}] ignore: '{rule}' does not apply to {} logs",
+                    source.name,
+                    kind.name()
+                ));
+            }
+
+            configs.push(SourceConfig {
+                kind,
+                paths,
+                ports: (!global_bans).then_some(ports),
+                policy,
+                ignores,
+            });
+        }
+
+        Ok(configs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Config;
+    use crate::{LogKind, guard::Policy};
+    use std::{path::PathBuf, time::Duration};
+
+    fn no_env(_: &str) -> Option<String> {
+        None
+    }
+
+    #[test]
+    fn test_defaults() {
+        let config = Config::parse("", &no_env).unwrap();
+
+        assert_eq!(config.sources[0].policy, Policy::default());
+        assert_eq!(
+            config.settings.history_path,
+            PathBuf::from("/var/lib/ubuntu-guard/ban_history.txt")
+        );
+        assert_eq!(config.sources.len(), 3);
+        assert_eq!(
+            config.sources[0].ports.as_ref().unwrap().to_string(),
+            "22/tcp"
+        );
+        assert!(!config.dry_run);
+        assert!(config.allow_local_addresses);
+        assert_eq!(config.rules_path, None);
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let text = r#"
+            [global]
+            threshold = 3
+            window_seconds = 600
+            recidive_factor = 2.5
+
+            [sources.ssh]
+            ports = "2222/tcp"
+            window_seconds = 3600
+            block_duration_seconds = 86400
+
+            [sources.nginx]
+            enabled = false
+
+            [sources.sites]
+            kind = "apache"
+            paths = ["/srv/www/*/logs/access.log", "/srv/www/*/logs/ssl_access.log"]
+            ports = "443"
+            ignore = ["prefix /admin/", "exact /api/config.xml", "query contains token="]
+
+            [sources."www.example.com"]
+            kind = "nginx"
+            paths = ["/srv/shop/access.log"]
+            threshold = 7
+
+            [firewall]
+            backend = "nftables"
+            action = "drop"
+
+            [allowlist]
+            entries = ["203.0.113.7", "10.0.0.0/8"]
+            local_addresses = false
+
+            [rules]
+            path = "/etc/ubuntu-guard/http.rules"
+            defaults = false
+
+            [output]
+            log_path = "/tmp/guard.log"
+        "#;
+
+        let config = Config::parse(text, &no_env).unwrap();
+        assert_eq!(
+            config.sources[0].policy,
+            Policy {
+                threshold: 3,
+                window: Duration::from_secs(3600),
+                block_duration: Duration::from_secs(86400),
+            }
+        );
+        assert_eq!(config.sources[1].policy.window, Duration::from_secs(600));
+        assert_eq!(config.settings.recidive.factor, 2.5);
+        assert_eq!(config.settings.log_path, PathBuf::from("/tmp/guard.log"));
+        assert!(matches!(
+            config.sources.as_slice(),
+            [ssh, apache, sites, shop] if matches!(ssh.kind, LogKind::Ssh)
+                && matches!(apache.kind, LogKind::Apache)
+                && matches!(sites.kind, LogKind::Apache)
+                && matches!(shop.kind, LogKind::Nginx)
+        ));
+        assert_eq!(config.sources[3].paths, ["/srv/shop/access.log"]);
+        assert_eq!(config.sources[3].policy.threshold, 7);
+        assert_eq!(config.sources[1].paths, ["/var/log/apache2/*access.log"]);
+        assert_eq!(config.sources[2].paths.len(), 2);
+        assert_eq!(config.sources[2].ignores.len(), 3);
+        assert!(config.sources[0].ignores.is_empty());
+        assert_eq!(
+            config.sources[2].ports.as_ref().unwrap().to_string(),
+            "443/tcp"
+        );
+        assert_eq!(
+            config.sources[0].ports.as_ref().unwrap().to_string(),
+            "2222/tcp"
+        );
+        assert_eq!(config.allowlist.len(), 2);
+        assert!(!config.allow_local_addresses);
+        assert_eq!(
+            config.rules_path,
+            Some(PathBuf::from("/etc/ubuntu-guard/http.rules"))
+        );
+        assert!(!config.default_rules);
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let env = |name: &str| match name {
+            "THRESHOLD" => Some("10".to_string()),
+            "GLOBAL_BANS" => Some("true".to_string()),
+            _ => None,
+        };
+
+        let config = Config::parse("[global]\nthreshold = 3", &env).unwrap();
+        assert_eq!(config.sources[0].policy.threshold, 10);
+        assert!(config.sources.iter().all(|source| source.ports.is_none()));
+    }
+
+    #[test]
+    fn test_ports_env_is_default() {
+        let env = |name: &str| (name == "HTTP_PORTS").then(|| "8080/tcp".to_string());
+        let text =
+            "[sources.sites]\nkind = \"nginx\"\npaths = [\"/srv/access.log\"]\nports = \"443\"";
+
+        let config = Config::parse(text, &env).unwrap();
+        let ports: Vec<String> = config
+            .sources
+            .iter()
+            .map(|source| source.ports.as_ref().unwrap().to_string())
+            .collect();
+        assert_eq!(ports, ["22/tcp", "8080/tcp", "8080/tcp", "443/tcp"]);
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let cases = [
+            (
+                "[global]\nthreshold = -1",
+                "[global] threshold: invalid value '-1'",
+            ),
+            (
+                "[global]\nthreshold = \"five\"",
+                "[global] threshold: invalid value 'five'",
+            ),
+            (
+                "[global]\nrecidive_factor = 0.5",
+                "[global] recidive_factor: '0.5' must be at least 1",
+            ),
+            (
+                "[global]\nrecidive_factor = nan",
+                "[global] recidive_factor: 'NaN' must be at least 1",
+            ),
+            (
+                "[global]\nthreshhold = 5",
+                "[global] unknown key 'threshhold'",
+            ),
+            (
+                "[sources.ftp]\npaths = \"/var/log/vsftpd.log\"",
+                "[sources.ftp] kind is required",
+            ),
+            (
+                "[sources.ftp]\nkind = \"ftp\"",
+                "[sources.ftp] kind: Unknown log kind 'ftp'",
+            ),
+            (
+                "[sources.sites]\nkind = \"nginx\"",
+                "[sources.sites] paths is required",
+            ),
+            (
+                "[sources.ssh]\npaths = \"auth.log\"",
+                "[sources.ssh] paths: 'auth.log' must be",
+            ),
+            (
+                "[sources.ssh]\npaths = [22]",
+                "[sources.ssh] paths: expected strings",
+            ),
+            (
+                "[sources.ssh]\ndir = \"/var/log\"",
+                "[sources.ssh] unknown key 'dir'",
+            ),
+            (
+                "[sources.apache]\nignore = \"prefix /admin 2\"",
+                "[sources.apache] ignore: 'prefix /admin 2': ignore rules have no weight",
+            ),
+            (
+                "[sources.ssh]\nignore = \"prefix /admin\"",
+                "[sources.ssh] ignore: 'prefix /admin' does not apply to ssh logs",
+            ),
+            (
+                "[sources.apache]\nignore = \"status exact 404 & ssh contains x\"",
+                "[sources.apache] ignore: 'status exact 404 & ssh contains x' does not apply to apache logs",
+            ),
+            (
+                "[firewall]\nbackend = \"pf\"",
+                "[firewall] backend: invalid value 'pf'",
+            ),
+            (
+                "[output]\nlog_path = 1979-05-27",
+                "[output] log_path: unsupported datetime value",
+            ),
+            (
+                "[rules]\npath = \"http.rules\"",
+                "[rules] path: 'http.rules' must be absolute",
+            ),
+            ("[logging]", "Unknown section [logging]"),
+            ("global = 5", "[global] must be a table"),
+        ];
+
+        for (text, expected) in cases {
+            let error = Config::parse(text, &no_env).err().unwrap();
+            assert!(
+                error.starts_with(expected),
+                "Unexpected error for {text:?}: {error}"
+            );
+        }
+    }
+}
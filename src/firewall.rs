@@ -0,0 +1,1069 @@
+use crate::{
+    ban::{Ban, PortScope, Proto},
+    net::IpNet,
+};
+use std::{
+    net::IpAddr,
+    process::{Command, ExitStatus},
+    str::FromStr,
+    time::Duration,
+};
+
+const NAME: &str = "ubuntu-guard";
+
+// Extra lifetime given to nftables/ipset set elements on top of the ban duration,
+// so the tracker always removes the element itself before the kernel expires it
+const TIMEOUT_SLACK: Duration = Duration::from_secs(300);
+
+// Largest timeout accepted by ipset
+const IPSET_MAX_TIMEOUT: u64 = 2_147_483;
+
+pub trait Firewall: Send {
+    /// Short name used in log messages
+    fn name(&self) -> &'static str;
+
+    /// Creates the chains, tables or sets used by the other calls
+    fn prepare(&self, log: &dyn Fn(&str));
+
+    /// Bans until unbanned, the duration is only a hint for backends with timeouts
+    /// and None for permanent bans
+    fn ban(&self, ban: &Ban, duration: Option<Duration>) -> Result<(), String>;
+
+    fn unban(&self, ban: &Ban) -> Result<(), String>;
+
+    /// Currently active bans
+    fn list(&self) -> Result<Vec<Ban>, String>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FirewallBackend {
+    Iptables,
+    Nftables,
+    Ipset,
+}
+
+impl FromStr for FirewallBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "iptables" => Ok(Self::Iptables),
+            "nftables" | "nft" => Ok(Self::Nftables),
+            "ipset" => Ok(Self::Ipset),
+            other => Err(format!("Unknown firewall backend '{other}'")),
+        }
+    }
+}
+
+impl FirewallBackend {
+    pub fn into_firewall(self, action: BanAction) -> Result<Box<dyn Firewall>, String> {
+        Ok(match self {
+            Self::Iptables => Box::new(Iptables { action }),
+            Self::Nftables => Box::new(Nftables {
+                statements: action.nft_statements()?,
+            }),
+            Self::Ipset => Box::new(Ipset { action }),
+        })
+    }
+}
+
+/// Verdict applied to traffic from banned IP addresses
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum BanAction {
+    Drop,
+    /// REJECT with an optional reject type, e.g. `tcp-reset` or `icmp-port-unreachable`
+    #[default]
+    Reject,
+    RejectWith(String),
+    /// TARPIT from xtables-addons for TCP, other protocols are dropped
+    Tarpit,
+    /// Any other iptables target, or a chain of the nftables table to jump to,
+    /// a name like `LOG_AND_DROP`
+    Custom(String),
+}
+
+impl FromStr for BanAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let lower = s.to_ascii_lowercase();
+
+        if let Some(reject_type) = lower.strip_prefix("reject:") {
+            return Ok(Self::RejectWith(reject_type.trim().to_string()));
+        }
+
+        match lower.as_str() {
+            "" => Err("Empty ban action".to_string()),
+            "drop" => Ok(Self::Drop),
+            "reject" => Ok(Self::Reject),
+            "tarpit" => Ok(Self::Tarpit),
+            _ if s
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') =>
+            {
+                Ok(Self::Custom(s.to_string()))
+            }
+            _ => Err(format!("Invalid ban action '{s}'")),
+        }
+    }
+}
+
+impl BanAction {
+    /// Rule endings for iptables or ip6tables, the first one has to match first
+    fn iptables_rules<'a>(&'a self, cmd: &str) -> Vec<Vec<&'a str>> {
+        match self {
+            Self::Drop => vec![vec!["-j", "DROP"]],
+            Self::Reject => vec![vec!["-j", "REJECT"]],
+            Self::RejectWith(t) if t == "tcp-reset" => vec![
+                vec!["-p", "tcp", "-j", "REJECT", "--reject-with", "tcp-reset"],
+                vec!["-j", "REJECT"],
+            ],
+            Self::RejectWith(t) if cmd == "ip6tables" => {
+                vec![vec!["-j", "REJECT", "--reject-with", ip6_reject_type(t)]]
+            }
+            Self::RejectWith(t) => vec![vec!["-j", "REJECT", "--reject-with", t]],
+            Self::Tarpit => vec![vec!["-p", "tcp", "-j", "TARPIT"], vec!["-j", "DROP"]],
+            Self::Custom(target) => vec![vec!["-j", target]],
+        }
+    }
+
+    /// Verdict statements for nftables, the first one has to match first
+    fn nft_statements(&self) -> Result<Vec<String>, String> {
+        let statements = match self {
+            Self::Drop => vec!["drop".to_string()],
+            Self::Reject => vec!["reject".to_string()],
+            Self::RejectWith(t) if t == "tcp-reset" => vec![
+                "meta l4proto tcp reject with tcp reset".to_string(),
+                "reject".to_string(),
+            ],
+            Self::RejectWith(t) => {
+                let icmpx = match t.as_str() {
+                    "icmp-port-unreachable" => "port-unreachable",
+                    "icmp-host-unreachable" => "host-unreachable",
+                    "icmp-net-unreachable" => "no-route",
+                    "icmp-admin-prohibited" | "icmp-host-prohibited" | "icmp-net-prohibited" => {
+                        "admin-prohibited"
+                    }
+                    other => return Err(format!("Unsupported reject type '{other}' for nftables")),
+                };
+                vec![format!("reject with icmpx type {icmpx}")]
+            }
+            Self::Tarpit => return Err("TARPIT is not supported by nftables".to_string()),
+            // The chain has to be added to the table by the user
+            Self::Custom(chain) => vec![format!("jump {chain}")],
+        };
+
+        Ok(statements)
+    }
+}
+
+// Limits rule endings to the destination ports, dropping rules for other protocols
+fn scoped_rules(rules: Vec<Vec<&str>>, ports: Option<&PortScope>) -> Vec<Vec<String>> {
+    let Some(ports) = ports else {
+        return rules
+            .into_iter()
+            .map(|rule| rule.into_iter().map(String::from).collect())
+            .collect();
+    };
+
+    let proto = ports.proto.as_str();
+    rules
+        .into_iter()
+        .filter_map(|rule| {
+            let mut args: Vec<String> = vec![
+                "-p".into(),
+                proto.into(),
+                "-m".into(),
+                "multiport".into(),
+                "--dports".into(),
+                ports.list(),
+            ];
+
+            let mut rule = rule.into_iter();
+            while let Some(arg) = rule.next() {
+                if arg == "-p" {
+                    if rule.next()? != proto {
+                        return None;
+                    }
+                } else {
+                    args.push(arg.into());
+                }
+            }
+
+            Some(args)
+        })
+        .collect()
+}
+
+// Groups (IP, protocol, port) set elements back into bans
+fn group_elements(elements: impl Iterator<Item = (IpNet, Option<(Proto, u16)>)>) -> Vec<Ban> {
+    let mut bans: Vec<Ban> = Vec::new();
+
+    for (net, port) in elements {
+        let Some((proto, port)) = port else {
+            bans.push(Ban::new(net, None));
+            continue;
+        };
+
+        let existing = bans.iter_mut().find(|ban| {
+            ban.net == net && ban.ports.as_ref().is_some_and(|ports| ports.proto == proto)
+        });
+
+        match existing.and_then(|ban| ban.ports.as_mut()) {
+            Some(ports) => {
+                *ports = PortScope::new(proto, [ports.ports.clone(), vec![port]].concat())
+            }
+            None => bans.push(Ban::new(net, Some(PortScope::new(proto, vec![port])))),
+        }
+    }
+
+    bans
+}
+
+// ip6tables names the ICMPv6 variants of the iptables reject types differently
+fn ip6_reject_type(reject_type: &str) -> &str {
+    match reject_type {
+        "icmp-port-unreachable" => "icmp6-port-unreachable",
+        "icmp-host-unreachable" => "icmp6-addr-unreachable",
+        "icmp-net-unreachable" => "icmp6-no-route",
+        "icmp-admin-prohibited" | "icmp-host-prohibited" | "icmp-net-prohibited" => {
+            "icmp6-adm-prohibited"
+        }
+        other => other,
+    }
+}
+
+fn sudo(cmd: &str) -> Command {
+    let mut command = Command::new("sudo");
+    command.arg(cmd);
+    command
+}
+
+fn check_status(status: std::io::Result<ExitStatus>) -> Result<(), String> {
+    match status {
+        Ok(s) if s.success() => Ok(()),
+        Ok(s) => Err(format!("exit code: {s}")),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn run_output(command: &mut Command) -> Result<String, String> {
+    match command.output() {
+        Ok(output) if output.status.success() => {
+            Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+        }
+        Ok(output) => Err(format!("exit code: {}", output.status)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// Rules of the chain as printed by `-S`, without the leading `-A ubuntu-guard`
+fn chain_rules(cmd: &str) -> Result<Vec<Vec<String>>, String> {
+    let rules = run_output(sudo(cmd).arg("-S").arg(NAME))?;
+    let prefix = format!("-A {NAME} ");
+
+    Ok(rules
+        .lines()
+        .filter_map(|line| line.strip_prefix(&prefix))
+        .map(|rule| rule.split_whitespace().map(String::from).collect())
+        .collect())
+}
+
+fn delete_rule(cmd: &str, rule: &[String]) -> Result<(), String> {
+    check_status(sudo(cmd).arg("-D").arg(NAME).args(rule).status())
+}
+
+fn create_and_link_chain(cmd: &str, log: &dyn Fn(&str)) {
+    let chain_exists = sudo(cmd)
+        .arg("-L")
+        .arg(NAME)
+        .status()
+        .map(|s| s.success())
+        .unwrap_or(false);
+
+    if !chain_exists {
+        log(&format!("Creating new chain {NAME} in {cmd}"));
+        if let Err(e) = sudo(cmd).arg("-N").arg(NAME).status() {
+            log(&format!("Failed to create chain {NAME} in {cmd}: {e}"));
+        }
+    }
+
+    let linked = sudo(cmd)
+        .arg("-C")
+        .arg("INPUT")
+        .arg("-j")
+        .arg(NAME)
+        .status()
+        .map(|s| s.success())
+        .unwrap_or(false);
+
+    if !linked {
+        log(&format!("Linking {NAME} to INPUT in {cmd}"));
+        if let Err(e) = sudo(cmd)
+            .arg("-I")
+            .arg("INPUT")
+            .arg("1") // Position 1 to reject banned IP addresses first
+            .arg("-j")
+            .arg(NAME)
+            .status()
+        {
+            log(&format!("Failed to link {NAME} to INPUT in {cmd}: {e}"));
+        }
+    }
+}
+
+/// Per-IP or per-network rules in the `ubuntu-guard` chain, linked first in INPUT
+pub struct Iptables {
+    action: BanAction,
+}
+
+impl Iptables {
+    fn cmd(ip: IpAddr) -> &'static str {
+        match ip {
+            IpAddr::V4(_) => "iptables",
+            IpAddr::V6(_) => "ip6tables",
+        }
+    }
+
+    fn rules(&self, ban: &Ban) -> Vec<Vec<String>> {
+        let rules = self.action.iptables_rules(Self::cmd(ban.net.addr()));
+        scoped_rules(rules, ban.ports.as_ref())
+    }
+
+    fn run(&self, action: &str, net: IpNet, rule: &[String]) -> Result<(), String> {
+        let status = sudo(Self::cmd(net.addr()))
+            .arg(action)
+            .arg(NAME)
+            .arg("-s")
+            .arg(net.to_string())
+            .args(rule)
+            .status();
+
+        check_status(status)
+    }
+}
+
+impl Firewall for Iptables {
+    fn name(&self) -> &'static str {
+        "iptables"
+    }
+
+    fn prepare(&self, log: &dyn Fn(&str)) {
+        create_and_link_chain("iptables", log);
+        create_and_link_chain("ip6tables", log);
+    }
+
+    fn ban(&self, ban: &Ban, _duration: Option<Duration>) -> Result<(), String> {
+        // Inserting at the top in reverse keeps the rules in order
+        self.rules(ban)
+            .iter()
+            .rev()
+            .try_for_each(|rule| self.run("-I", ban.net, rule))
+    }
+
+    /// Deletes the rules of the ban whatever their target, so bans made with a
+    /// previous ban action are removed too
+    fn unban(&self, ban: &Ban) -> Result<(), String> {
+        let cmd = Self::cmd(ban.net.addr());
+        let rules: Vec<Vec<String>> = chain_rules(cmd)?
+            .into_iter()
+            .filter(|rule| parse_iptables_rule(&rule.join(" ")).as_ref() == Some(ban))
+            .collect();
+
+        if rules.is_empty() {
+            return Err(format!("No rule for {ban} in {cmd}"));
+        }
+        rules.iter().try_for_each(|rule| delete_rule(cmd, rule))
+    }
+
+    fn list(&self) -> Result<Vec<Ban>, String> {
+        let mut bans = Vec::new();
+
+        for cmd in ["iptables", "ip6tables"] {
+            let rules = run_output(sudo(cmd).arg("-S").arg(NAME))?;
+            for ban in parse_iptables_rules(&rules) {
+                if !bans.contains(&ban) {
+                    bans.push(ban);
+                }
+            }
+        }
+
+        Ok(bans)
+    }
+}
+
+// -A ubuntu-guard -s 1.2.3.4/32 -j REJECT --reject-with icmp-port-unreachable
+// -A ubuntu-guard -s 1.2.3.4/32 -p tcp -m multiport --dports 80,443 -j REJECT
+fn parse_iptables_rules(rules: &str) -> Vec<Ban> {
+    rules.lines().filter_map(parse_iptables_rule).collect()
+}
+
+// Ban of a single rule, the target is ignored
+fn parse_iptables_rule(rule: &str) -> Option<Ban> {
+    let mut ip = None;
+    let mut proto = None;
+    let mut ports = None;
+
+    let mut tokens = rule.split_whitespace();
+    while let Some(token) = tokens.next() {
+        match token {
+            "-s" => ip = tokens.next(),
+            "-p" => proto = tokens.next(),
+            "--dports" => ports = tokens.next(),
+            _ => {}
+        }
+    }
+
+    let net = ip?.parse().ok()?;
+    let ports = match (ports, proto) {
+        (Some(ports), Some(proto)) => Some(format!("{ports}/{proto}").parse().ok()?),
+        _ => None,
+    };
+
+    Some(Ban::new(net, ports))
+}
+
+/// Timeout-enabled IPv4/IPv6 interval sets in an `inet ubuntu-guard` table,
+/// port-scoped bans use sets of IP address or network, protocol and port
+pub struct Nftables {
+    statements: Vec<String>,
+}
+
+impl Nftables {
+    fn set(ban: &Ban) -> &'static str {
+        match (ban.net.addr(), &ban.ports) {
+            (IpAddr::V4(_), None) => "banned_v4",
+            (IpAddr::V6(_), None) => "banned_v6",
+            (IpAddr::V4(_), Some(_)) => "banned_ports_v4",
+            (IpAddr::V6(_), Some(_)) => "banned_ports_v6",
+        }
+    }
+
+    fn elements(ban: &Ban, timeout: Option<u64>) -> String {
+        let timeout = timeout
+            .map(|t| format!(" timeout {t}s"))
+            .unwrap_or_default();
+
+        let elements: Vec<String> = match &ban.ports {
+            Some(ports) => ports
+                .ports
+                .iter()
+                .map(|port| format!("{} . {} . {port}{timeout}", ban.net, ports.proto.as_str()))
+                .collect(),
+            None => vec![format!("{}{timeout}", ban.net)],
+        };
+
+        elements.join(", ")
+    }
+
+    fn run(&self, command: &str) -> Result<(), String> {
+        check_status(sudo("nft").arg(command).status())
+    }
+}
+
+impl Firewall for Nftables {
+    fn name(&self) -> &'static str {
+        "nft"
+    }
+
+    fn prepare(&self, log: &dyn Fn(&str)) {
+        if self.run(&format!("list table inet {NAME}")).is_err() {
+            log(&format!("Creating new table inet {NAME} in nft"));
+        }
+
+        // Adding existing objects is a no-op, the rules are rebuilt to apply the ban action
+        let mut commands = vec![
+            format!("add table inet {NAME}"),
+            format!("add set inet {NAME} banned_v4 {{ type ipv4_addr; flags interval, timeout; }}"),
+            format!("add set inet {NAME} banned_v6 {{ type ipv6_addr; flags interval, timeout; }}"),
+            format!(
+                "add set inet {NAME} banned_ports_v4 {{ type ipv4_addr . inet_proto . inet_service; flags interval, timeout; }}"
+            ),
+            format!(
+                "add set inet {NAME} banned_ports_v6 {{ type ipv6_addr . inet_proto . inet_service; flags interval, timeout; }}"
+            ),
+            // Priority below 0 to reject banned IP addresses before other filter chains
+            format!(
+                "add chain inet {NAME} input {{ type filter hook input priority -10; policy accept; }}"
+            ),
+            format!("flush chain inet {NAME} input"),
+        ];
+
+        let matchers = [
+            ("ip saddr", "banned_v4"),
+            ("ip6 saddr", "banned_v6"),
+            ("ip saddr . meta l4proto . th dport", "banned_ports_v4"),
+            ("ip6 saddr . meta l4proto . th dport", "banned_ports_v6"),
+        ];
+
+        for (matcher, set) in matchers {
+            for statement in &self.statements {
+                commands.push(format!(
+                    "add rule inet {NAME} input {matcher} @{set} {statement}"
+                ));
+            }
+        }
+
+        for command in commands {
+            if let Err(e) = self.run(&command) {
+                log(&format!("Failed to run 'nft {command}': {e}"));
+            }
+        }
+    }
+
+    fn ban(&self, ban: &Ban, duration: Option<Duration>) -> Result<(), String> {
+        let timeout = duration.map(|d| d.saturating_add(TIMEOUT_SLACK).as_secs());
+        self.run(&format!(
+            "add element inet {NAME} {} {{ {} }}",
+            Self::set(ban),
+            Self::elements(ban, timeout)
+        ))
+    }
+
+    fn unban(&self, ban: &Ban) -> Result<(), String> {
+        self.run(&format!(
+            "delete element inet {NAME} {} {{ {} }}",
+            Self::set(ban),
+            Self::elements(ban, None)
+        ))
+    }
+
+    fn list(&self) -> Result<Vec<Ban>, String> {
+        let mut bans = Vec::new();
+
+        for set in [
+            "banned_v4",
+            "banned_v6",
+            "banned_ports_v4",
+            "banned_ports_v6",
+        ] {
+            let output = run_output(sudo("nft").arg(format!("list set inet {NAME} {set}")))?;
+            bans.extend(parse_nft_elements(&output));
+        }
+
+        Ok(bans)
+    }
+}
+
+// elements = { 1.2.3.4 timeout 1h5m expires 59m58s, 5.6.7.0/24 timeout 1h5m expires 1h }
+// elements = { 1.2.3.4 . tcp . 80 timeout 1h5m expires 1h }
+fn parse_nft_elements(output: &str) -> Vec<Ban> {
+    let Some(start) = output.find("elements = {") else {
+        return Vec::new();
+    };
+    let elements = &output[start + "elements = {".len()..];
+    let elements = elements.split('}').next().unwrap_or_default();
+
+    group_elements(elements.split(',').filter_map(|element| {
+        let mut tokens = element.split_whitespace();
+        let net = tokens.next()?.parse().ok()?;
+
+        if tokens.next() != Some(".") {
+            return Some((net, None));
+        }
+        let proto = tokens.next()?.parse().ok()?;
+        tokens.next()?;
+        let port = tokens.next()?.parse().ok()?;
+
+        Some((net, Some((proto, port))))
+    }))
+}
+
+// Command, name, type and family of the ipset sets, networks are kept as single entries
+const IPSET_SETS: [(&str, &str, &str, &str); 4] = [
+    ("iptables", "ubuntu-guard-v4", "hash:net", "inet"),
+    ("ip6tables", "ubuntu-guard-v6", "hash:net", "inet6"),
+    ("iptables", "ubuntu-guard-ports-v4", "hash:net,port", "inet"),
+    (
+        "ip6tables",
+        "ubuntu-guard-ports-v6",
+        "hash:net,port",
+        "inet6",
+    ),
+];
+
+/// `hash:net` sets matched by a single rule per family in the `ubuntu-guard` chain,
+/// port-scoped bans use `hash:net,port` sets
+pub struct Ipset {
+    action: BanAction,
+}
+
+impl Ipset {
+    fn set(ban: &Ban) -> &'static str {
+        match (ban.net.addr(), &ban.ports) {
+            (IpAddr::V4(_), None) => "ubuntu-guard-v4",
+            (IpAddr::V6(_), None) => "ubuntu-guard-v6",
+            (IpAddr::V4(_), Some(_)) => "ubuntu-guard-ports-v4",
+            (IpAddr::V6(_), Some(_)) => "ubuntu-guard-ports-v6",
+        }
+    }
+
+    fn entries(ban: &Ban) -> Vec<String> {
+        match &ban.ports {
+            Some(ports) => ports
+                .ports
+                .iter()
+                .map(|port| format!("{},{}:{port}", ban.net, ports.proto.as_str()))
+                .collect(),
+            None => vec![ban.net.to_string()],
+        }
+    }
+
+    fn create_set(&self, cmd: &str, set: &str, kind: &str, family: &str, log: &dyn Fn(&str)) {
+        let status = sudo("ipset")
+            .args(ipset_create_args(set, kind, family))
+            .status();
+
+        if let Err(e) = check_status(status) {
+            return log(&format!("Failed to create ipset {set}: {e}"));
+        }
+
+        // The match rules are replaced whatever their target, the ban action may have changed
+        let stale = chain_rules(cmd).map(|rules| {
+            rules
+                .into_iter()
+                .filter(|rule| {
+                    rule.windows(2)
+                        .any(|w| w[0] == "--match-set" && w[1] == set)
+                })
+                .collect::<Vec<_>>()
+        });
+        match stale {
+            Ok(stale) => {
+                for rule in stale {
+                    if let Err(e) = delete_rule(cmd, &rule) {
+                        log(&format!(
+                            "Failed to delete '{}' in {cmd}: {e}",
+                            rule.join(" ")
+                        ));
+                    }
+                }
+            }
+            Err(e) => log(&format!("Failed to list {NAME} in {cmd}: {e}")),
+        }
+
+        let direction = if kind == "hash:net" { "src" } else { "src,dst" };
+        let matcher = ["-m", "set", "--match-set", set, direction];
+        log(&format!("Matching ipset {set} in {NAME} in {cmd}"));
+        for rule in self.action.iptables_rules(cmd).iter().rev() {
+            let status = sudo(cmd)
+                .arg("-I")
+                .arg(NAME)
+                .args(matcher)
+                .args(rule)
+                .status();
+
+            if let Err(e) = check_status(status) {
+                log(&format!("Failed to match ipset {set} in {cmd}: {e}"));
+            }
+        }
+    }
+}
+
+// Timeout 0 is permanent, longer bans than ipset accepts are left to the unban
+fn ipset_timeout(duration: Option<Duration>) -> u64 {
+    duration
+        .map(|d| d.saturating_add(TIMEOUT_SLACK).as_secs())
+        .filter(|&timeout| timeout <= IPSET_MAX_TIMEOUT)
+        .unwrap_or(0)
+}
+
+// Timeout 0 enables per-element timeouts with permanent entries by default
+fn ipset_create_args<'a>(set: &'a str, kind: &'a str, family: &'a str) -> [&'a str; 8] {
+    [
+        "create", set, kind, "family", family, "timeout", "0", "-exist",
+    ]
+}
+
+impl Firewall for Ipset {
+    fn name(&self) -> &'static str {
+        "ipset"
+    }
+
+    fn prepare(&self, log: &dyn Fn(&str)) {
+        create_and_link_chain("iptables", log);
+        create_and_link_chain("ip6tables", log);
+
+        for (cmd, set, kind, family) in IPSET_SETS {
+            self.create_set(cmd, set, kind, family, log);
+        }
+    }
+
+    fn ban(&self, ban: &Ban, duration: Option<Duration>) -> Result<(), String> {
+        let timeout = ipset_timeout(duration);
+
+        Self::entries(ban).iter().try_for_each(|entry| {
+            let status = sudo("ipset")
+                .args(["add", Self::set(ban), entry])
+                .args(["timeout", &timeout.to_string(), "-exist"])
+                .status();
+
+            check_status(status)
+        })
+    }
+
+    fn unban(&self, ban: &Ban) -> Result<(), String> {
+        Self::entries(ban).iter().try_for_each(|entry| {
+            let status = sudo("ipset")
+                .args(["del", Self::set(ban), entry, "-exist"])
+                .status();
+
+            check_status(status)
+        })
+    }
+
+    fn list(&self) -> Result<Vec<Ban>, String> {
+        let mut bans = Vec::new();
+
+        let sets = [
+            "ubuntu-guard-v4",
+            "ubuntu-guard-v6",
+            "ubuntu-guard-ports-v4",
+            "ubuntu-guard-ports-v6",
+        ];
+
+        for set in sets {
+            let output = run_output(sudo("ipset").args(["list", set]))?;
+            bans.extend(parse_ipset_members(&output));
+        }
+
+        Ok(bans)
+    }
+}
+
+// Members:
+// 1.2.3.4 timeout 3597
+// 1.2.3.0/24,tcp:80 timeout 3597
+fn parse_ipset_members(output: &str) -> Vec<Ban> {
+    let members = output
+        .lines()
+        .skip_while(|line| !line.starts_with("Members:"))
+        .skip(1)
+        .filter_map(|line| {
+            let member = line.split_whitespace().next()?;
+
+            let Some((ip, port)) = member.rsplit_once(',') else {
+                return Some((member.parse().ok()?, None));
+            };
+            let (proto, port) = port.split_once(':')?;
+
+            Some((
+                ip.parse().ok()?,
+                Some((proto.parse().ok()?, port.parse().ok()?)),
+            ))
+        });
+
+    group_elements(members)
+}
+
+#[cfg(test)]
+pub use recording::{Call, RecordingFirewall};
+
+#[cfg(test)]
+mod recording {
+    use super::Firewall;
+    use crate::ban::Ban;
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub enum Call {
+        Prepare,
+        Ban(Ban, Option<Duration>),
+        Unban(Ban),
+    }
+
+    /// In-memory firewall which records every call, cloned handles share state
+    #[derive(Clone, Default)]
+    pub struct RecordingFirewall {
+        calls: Arc<Mutex<Vec<Call>>>,
+        banned: Arc<Mutex<Vec<Ban>>>,
+    }
+
+    impl RecordingFirewall {
+        pub fn calls(&self) -> Vec<Call> {
+            self.calls.lock().unwrap().clone()
+        }
+
+        pub fn banned(&self) -> Vec<Ban> {
+            self.banned.lock().unwrap().clone()
+        }
+    }
+
+    impl Firewall for RecordingFirewall {
+        fn name(&self) -> &'static str {
+            "recording"
+        }
+
+        fn prepare(&self, _log: &dyn Fn(&str)) {
+            self.calls.lock().unwrap().push(Call::Prepare);
+        }
+
+        fn ban(&self, ban: &Ban, duration: Option<Duration>) -> Result<(), String> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(Call::Ban(ban.clone(), duration));
+            self.banned.lock().unwrap().push(ban.clone());
+            Ok(())
+        }
+
+        fn unban(&self, ban: &Ban) -> Result<(), String> {
+            self.calls.lock().unwrap().push(Call::Unban(ban.clone()));
+            let mut banned = self.banned.lock().unwrap();
+            match banned.iter().position(|b| b == ban) {
+                Some(i) => {
+                    banned.remove(i);
+                    Ok(())
+                }
+                None => Err(format!("{ban} is not banned")),
+            }
+        }
+
+        fn list(&self) -> Result<Vec<Ban>, String> {
+            Ok(self.banned())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        BanAction, IPSET_MAX_TIMEOUT, IPSET_SETS, ipset_create_args, ipset_timeout,
+        parse_ipset_members, parse_iptables_rule, parse_iptables_rules, parse_nft_elements,
+        scoped_rules,
+    };
+    use crate::ban::Ban;
+    use std::time::Duration;
+
+    fn bans(bans: &[&str]) -> Vec<Ban> {
+        bans.iter().map(|b| b.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_parse_iptables_rules() {
+        let rules = "-N ubuntu-guard\n\
+            -A ubuntu-guard -s 1.2.3.4/32 -j REJECT --reject-with icmp-port-unreachable\n\
+            -A ubuntu-guard -s 2001:db8::1/128 -j REJECT\n\
+            -A ubuntu-guard -s 5.6.7.8/32 -p tcp -m multiport --dports 80,443 -j DROP\n\
+            -A ubuntu-guard -s 9.9.9.9/32 -p tcp -j REJECT --reject-with tcp-reset\n\
+            -A ubuntu-guard -s 10.0.0.0/24 -j REJECT\n";
+
+        assert_eq!(
+            parse_iptables_rules(rules),
+            bans(&[
+                "1.2.3.4",
+                "2001:db8::1",
+                "5.6.7.8@80,443/tcp",
+                "9.9.9.9",
+                "10.0.0.0/24"
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_iptables_rule_ignores_target() {
+        let ban = "5.6.7.8@80,443/tcp".parse().ok();
+        for rule in [
+            "-s 5.6.7.8/32 -p tcp -m multiport --dports 80,443 -j DROP",
+            "-s 5.6.7.8/32 -p tcp -m multiport --dports 80,443 -j TARPIT",
+            "-s 5.6.7.8/32 -p tcp -m multiport --dports 80,443 -j REJECT --reject-with tcp-reset",
+        ] {
+            assert_eq!(parse_iptables_rule(rule), ban, "{rule}");
+        }
+    }
+
+    #[test]
+    fn test_parse_nft_elements() {
+        let output = "table inet ubuntu-guard {\n\
+            \tset banned_v4 {\n\
+            \t\ttype ipv4_addr\n\
+            \t\tflags timeout\n\
+            \t\telements = { 1.2.3.4 timeout 1h5m expires 59m58s, 5.6.7.0/24 timeout 1h5m expires 1h }\n\
+            \t}\n\
+            }\n";
+
+        assert_eq!(parse_nft_elements(output), bans(&["1.2.3.4", "5.6.7.0/24"]));
+        assert!(parse_nft_elements("set banned_v6 {\n}").is_empty());
+
+        let output = "\t\telements = { 1.2.3.4 . tcp . 80 timeout 1h expires 1h,\n\
+            \t\t\t     1.2.3.4 . tcp . 443 timeout 1h expires 1h,\n\
+            \t\t\t     5.6.7.8 . tcp . 22 timeout 1h expires 1h }\n";
+
+        assert_eq!(
+            parse_nft_elements(output),
+            bans(&["1.2.3.4@80,443/tcp", "5.6.7.8@22/tcp"])
+        );
+    }
+
+    #[test]
+    fn test_parse_ipset_members() {
+        let output = "Name: ubuntu-guard-v4\n\
+            Type: hash:net\n\
+            Header: family inet hashsize 1024 maxelem 65536 timeout 0\n\
+            Number of entries: 2\n\
+            Members:\n\
+            1.2.3.4 timeout 3597\n\
+            5.6.7.8 timeout 12\n";
+
+        assert_eq!(parse_ipset_members(output), bans(&["1.2.3.4", "5.6.7.8"]));
+
+        let output = "Members:\n\
+            2001:db8::1,tcp:443 timeout 3597\n\
+            2001:db8::1,tcp:80 timeout 3597\n\
+            2001:db8:1::/64,tcp:22 timeout 3597\n";
+
+        assert_eq!(
+            parse_ipset_members(output),
+            bans(&["2001:db8::1@80,443/tcp", "2001:db8:1::/64@22/tcp"])
+        );
+    }
+
+    #[test]
+    fn test_ipset_create_args() {
+        let args: Vec<_> = IPSET_SETS
+            .iter()
+            .map(|&(_, set, kind, family)| ipset_create_args(set, kind, family).join(" "))
+            .collect();
+
+        assert_eq!(
+            args,
+            [
+                "create ubuntu-guard-v4 hash:net family inet timeout 0 -exist",
+                "create ubuntu-guard-v6 hash:net family inet6 timeout 0 -exist",
+                "create ubuntu-guard-ports-v4 hash:net,port family inet timeout 0 -exist",
+                "create ubuntu-guard-ports-v6 hash:net,port family inet6 timeout 0 -exist",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ipset_timeout() {
+        let timeouts = [
+            Some(Duration::from_secs(3600)),
+            Some(Duration::from_secs(IPSET_MAX_TIMEOUT - 300)),
+            Some(Duration::from_secs(IPSET_MAX_TIMEOUT)),
+            Some(Duration::MAX),
+            None,
+        ]
+        .map(ipset_timeout);
+
+        assert_eq!(timeouts, [3900, IPSET_MAX_TIMEOUT, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_ban_action() {
+        let cases = [
+            ("DROP", BanAction::Drop),
+            ("reject", BanAction::Reject),
+            (
+                "reject:tcp-reset",
+                BanAction::RejectWith("tcp-reset".to_string()),
+            ),
+            ("TARPIT", BanAction::Tarpit),
+            (
+                "LOG_AND_DROP",
+                BanAction::Custom("LOG_AND_DROP".to_string()),
+            ),
+        ];
+
+        for (s, expected) in cases {
+            assert_eq!(
+                s.parse::<BanAction>(),
+                Ok(expected),
+                "Action that failed: {s}"
+            );
+        }
+        assert!("".parse::<BanAction>().is_err());
+        assert_eq!(
+            "DROP; flush ruleset".parse::<BanAction>(),
+            Err("Invalid ban action 'DROP; flush ruleset'".to_string())
+        );
+        assert!("counter drop".parse::<BanAction>().is_err());
+    }
+
+    #[test]
+    fn test_ban_action_rules() {
+        let reset = BanAction::RejectWith("tcp-reset".to_string());
+        assert_eq!(
+            reset.iptables_rules("iptables"),
+            vec![
+                vec!["-p", "tcp", "-j", "REJECT", "--reject-with", "tcp-reset"],
+                vec!["-j", "REJECT"],
+            ]
+        );
+
+        let unreachable = BanAction::RejectWith("icmp-port-unreachable".to_string());
+        assert_eq!(
+            unreachable.iptables_rules("ip6tables"),
+            vec![vec![
+                "-j",
+                "REJECT",
+                "--reject-with",
+                "icmp6-port-unreachable"
+            ]]
+        );
+        assert_eq!(
+            unreachable.nft_statements(),
+            Ok(vec!["reject with icmpx type port-unreachable".to_string()])
+        );
+
+        assert!(BanAction::Tarpit.nft_statements().is_err());
+        assert_eq!(
+            BanAction::Custom("LOG_AND_DROP".to_string()).nft_statements(),
+            Ok(vec!["jump LOG_AND_DROP".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_scoped_rules() {
+        let tcp = "80,443/tcp".parse().ok();
+        let udp = "53/udp".parse().ok();
+
+        assert_eq!(
+            scoped_rules(BanAction::Tarpit.iptables_rules("iptables"), tcp.as_ref()),
+            vec![
+                vec![
+                    "-p",
+                    "tcp",
+                    "-m",
+                    "multiport",
+                    "--dports",
+                    "80,443",
+                    "-j",
+                    "TARPIT"
+                ],
+                vec![
+                    "-p",
+                    "tcp",
+                    "-m",
+                    "multiport",
+                    "--dports",
+                    "80,443",
+                    "-j",
+                    "DROP"
+                ],
+            ]
+        );
+        assert_eq!(
+            scoped_rules(BanAction::Tarpit.iptables_rules("iptables"), udp.as_ref()),
+            vec![vec![
+                "-p",
+                "udp",
+                "-m",
+                "multiport",
+                "--dports",
+                "53",
+                "-j",
+                "DROP"
+            ]]
+        );
+        assert_eq!(
+            scoped_rules(BanAction::Drop.iptables_rules("iptables"), None),
+            vec![vec!["-j", "DROP"]]
+        );
+    }
+}
@@ -0,0 +1,119 @@
+use std::{
+    fs::read_dir,
+    path::{Path, PathBuf},
+};
+
+pub fn has_wildcards(pattern: &str) -> bool {
+    pattern.contains(['*', '?'])
+}
+
+/// Matches a single path component, `*` is any run of characters and `?` any one character
+pub fn matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    let (mut p, mut n) = (0, 0);
+    // Position after the last `*` and the name position it was matched up to
+    let mut star = None;
+
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            p += 1;
+            star = Some((p, n));
+        } else if let Some((star_p, star_n)) = star {
+            // Let the last `*` match one more character
+            p = star_p;
+            n = star_n + 1;
+            star = Some((star_p, n));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Existing directories matching the pattern, any component may have wildcards
+pub fn expand_dirs(pattern: &Path) -> Vec<PathBuf> {
+    let mut dirs = vec![PathBuf::new()];
+
+    for component in pattern.components() {
+        let name = component.as_os_str().to_string_lossy();
+
+        if !has_wildcards(&name) {
+            for dir in &mut dirs {
+                dir.push(component);
+            }
+            continue;
+        }
+
+        dirs = dirs
+            .iter()
+            .flat_map(|dir| read_dir(dir).into_iter().flatten().flatten())
+            .filter(|entry| {
+                entry.path().is_dir() && matches(&name, &entry.file_name().to_string_lossy())
+            })
+            .map(|entry| entry.path())
+            .collect();
+    }
+
+    dirs.retain(|dir| dir.is_dir());
+    dirs.sort();
+    dirs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{expand_dirs, matches};
+    use std::{
+        env::temp_dir,
+        fs::{create_dir_all, remove_dir_all},
+    };
+
+    #[test]
+    fn test_matches() {
+        let cases = [
+            ("*access.log", "access.log", true),
+            ("*access.log", "shop_access.log", true),
+            ("*access.log", "access.log.1", false),
+            ("*.log", "error.log", true),
+            ("auth.log", "auth.log", true),
+            ("auth.log", "auth.log.1", false),
+            ("*_*.log", "a_b.log", true),
+            ("*_*.log", "ab.log", false),
+            ("site?.log", "site1.log", true),
+            ("site?.log", "site12.log", false),
+            ("*", "", true),
+            ("*a*a", "banana", true),
+        ];
+
+        for (pattern, name, expected) in cases {
+            assert_eq!(matches(pattern, name), expected, "{pattern} against {name}");
+        }
+    }
+
+    #[test]
+    fn test_expand_dirs() {
+        let root = temp_dir().join(format!("ubuntu-guard-glob-{}", std::process::id()));
+        let _ = remove_dir_all(&root);
+        for site in ["a.example", "b.example", "c.example/nologs"] {
+            create_dir_all(root.join("www").join(site).join("logs")).unwrap();
+        }
+        create_dir_all(root.join("www/c.example/other")).unwrap();
+
+        assert_eq!(
+            expand_dirs(&root.join("www/*/logs")),
+            vec![
+                root.join("www/a.example/logs"),
+                root.join("www/b.example/logs")
+            ]
+        );
+        assert_eq!(expand_dirs(&root.join("www")), vec![root.join("www")]);
+        assert!(expand_dirs(&root.join("missing")).is_empty());
+
+        remove_dir_all(&root).unwrap();
+    }
+}
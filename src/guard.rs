@@ -1,14 +1,23 @@
+use crate::{
+    allowlist::Allowlist,
+    ban::{Ban, PortScope},
+    firewall::Firewall,
+    net::IpNet,
+    reader::lossy_lines,
+};
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{HashMap, VecDeque, hash_map::Entry},
     fmt::Write as FmtWrite,
     fs::{File, OpenOptions, create_dir_all},
-    io::{BufRead, BufReader, BufWriter, Write},
+    io::{BufReader, BufWriter, Write},
     net::IpAddr,
     path::{Path, PathBuf},
-    process::Command,
     time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
+// Matched events kept per IP address and policy for the reason of its ban
+const EVENTS_PER_BAN: usize = 5;
+
 fn make_parent_dir(path: &Path) -> std::io::Result<()> {
     if let Some(parent) = path.parent() {
         create_dir_all(parent)?;
@@ -25,7 +34,7 @@ fn new_date_str() -> String {
 
     let secs = datetime.as_secs();
     let tm = time::OffsetDateTime::from_unix_timestamp(secs as i64)
-        .unwrap_or_else(|_| time::OffsetDateTime::UNIX_EPOCH);
+        .unwrap_or(time::OffsetDateTime::UNIX_EPOCH);
 
     format!(
         "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
@@ -38,40 +47,192 @@ fn new_date_str() -> String {
     )
 }
 
+/// Escalation of ban durations for repeat offenders
+#[derive(Clone, Copy, Debug)]
+pub struct Recidive {
+    /// Multiplier applied for every previous ban, 1 disables escalation
+    pub factor: f64,
+    /// Longest escalated ban duration
+    pub max_duration: Duration,
+    /// Bans beyond this count are permanent, 0 disables permanent bans
+    pub permanent_after: u32,
+}
+
+impl Default for Recidive {
+    fn default() -> Self {
+        Self {
+            factor: 1.0,
+            max_duration: Duration::MAX,
+            permanent_after: 0,
+        }
+    }
+}
+
+impl Recidive {
+    /// Duration of the given ban count (1 for a first offence), None if permanent
+    pub fn duration(&self, base: Duration, count: u32) -> Option<Duration> {
+        if self.permanent_after > 0 && count > self.permanent_after {
+            return None;
+        }
+
+        let factor = self.factor.powi(count.saturating_sub(1) as i32);
+        let max = self.max_duration.max(base);
+        let secs = (base.as_secs_f64() * factor).min(max.as_secs_f64());
+
+        // Too long for a Duration, e.g. the default maximum as f64 rounds up
+        Some(Duration::try_from_secs_f64(secs).map_or(max, |duration| duration.min(max)))
+    }
+}
+
+/// Replaces bans of many addresses from one network with a single network ban
+#[derive(Clone, Copy, Debug)]
+pub struct Subnet {
+    /// Distinct banned addresses within the window needed, 0 disables aggregation
+    pub threshold: u32,
+    pub window: Duration,
+    pub prefix_v4: u8,
+    pub prefix_v6: u8,
+}
+
+impl Default for Subnet {
+    fn default() -> Self {
+        Self {
+            threshold: 0,
+            window: Duration::from_secs(3600),
+            prefix_v4: 24,
+            prefix_v6: 48,
+        }
+    }
+}
+
+impl Subnet {
+    /// Network the address or network is aggregated into, None if it is not narrower
+    fn network(&self, net: IpNet) -> Option<IpNet> {
+        let prefix = match net.addr() {
+            IpAddr::V4(_) => self.prefix_v4,
+            IpAddr::V6(_) => self.prefix_v6,
+        };
+
+        if net.prefix() <= prefix {
+            return None;
+        }
+        IpNet::new(net.addr(), prefix).ok()
+    }
+}
+
+/// Bans once the weights of the attempts within the window reach `threshold`, set per log source
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Policy {
+    pub threshold: u64,
+    pub window: Duration,
+    pub block_duration: Duration,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            threshold: 5,
+            window: Duration::from_secs(60),
+            block_duration: Duration::from_secs(3600),
+        }
+    }
+}
+
+pub struct Settings {
+    pub recidive: Recidive,
+    pub subnet: Subnet,
+    /// IPv6 attempts are counted and banned per network of this prefix length
+    pub ipv6_prefix: u8,
+    pub banned_ip_path: PathBuf,
+    pub history_path: PathBuf,
+    pub log_path: PathBuf,
+}
+
 pub struct GuardTracker {
-    attempts: HashMap<String, VecDeque<Instant>>,
-    blocklist: HashMap<String, Instant>,
-    threshold: u64,
-    window: Duration,
-    block_duration: Duration,
+    // Weighted attempts, counted separately for every policy
+    attempts: HashMap<(Ban, Policy), VecDeque<(Instant, u64)>>,
+    // None for permanent bans
+    blocklist: HashMap<Ban, Option<Instant>>,
+    // Why each ban in the blocklist was made
+    reasons: HashMap<Ban, String>,
+    // Last matched events of the attempts, keyed like them so the reason of a ban
+    // only lists events of its ports and policy
+    events: HashMap<(Ban, Policy), VecDeque<String>>,
+    // Number of times each IP address or network has been banned
+    history: HashMap<IpNet, u32>,
+    // Recent bans of addresses from each network, for subnet aggregation
+    recent_bans: HashMap<Ban, VecDeque<(Ban, Instant)>>,
+    // Bans replaced by an active network ban, restored when it expires.
+    // Not persisted, so a restart forgets them
+    aggregated: HashMap<Ban, Vec<(Ban, Option<Instant>)>>,
+    recidive: Recidive,
+    subnet: Subnet,
+    ipv6_prefix: u8,
     banned_ip_path: PathBuf,
+    history_path: PathBuf,
     log_path: PathBuf,
+    // None in dry-run mode, bans are only logged
+    firewall: Option<Box<dyn Firewall>>,
+    allowlist: Allowlist,
+    // When a match of each allowlisted address or network was last logged
+    allowlist_logged: HashMap<IpNet, Instant>,
 }
 
 impl GuardTracker {
     pub fn new(
-        threshold: u64,
-        window: Duration,
-        block_duration: Duration,
-        banned_ip_path: String,
-        log_path: String,
+        settings: Settings,
+        firewall: Option<Box<dyn Firewall>>,
+        allowlist: Allowlist,
     ) -> Self {
         Self {
             attempts: HashMap::new(),
             blocklist: HashMap::new(),
-            threshold,
-            window,
-            block_duration,
-            banned_ip_path: PathBuf::from(banned_ip_path),
-            log_path: PathBuf::from(log_path),
+            reasons: HashMap::new(),
+            events: HashMap::new(),
+            history: HashMap::new(),
+            recent_bans: HashMap::new(),
+            aggregated: HashMap::new(),
+            recidive: settings.recidive,
+            subnet: settings.subnet,
+            ipv6_prefix: settings.ipv6_prefix,
+            banned_ip_path: settings.banned_ip_path,
+            history_path: settings.history_path,
+            log_path: settings.log_path,
+            firewall,
+            allowlist,
+            allowlist_logged: HashMap::new(),
+        }
+    }
+
+    /// Whether the IP address or its network is banned globally or from the given ports
+    pub fn is_blocked(&mut self, ip: IpAddr, ports: Option<&PortScope>) -> bool {
+        let key = self.attempt_net(ip);
+        let nets = [Some(IpNet::host(ip)), Some(key), self.subnet.network(key)];
+
+        nets.into_iter().flatten().any(|net| {
+            self.check_ban(&Ban::new(net, None))
+                || ports.is_some_and(|ports| self.check_ban(&Ban::new(net, Some(ports.clone()))))
+        })
+    }
+
+    fn log_allowlisted(&mut self, ip: IpAddr, policy: &Policy, event: &str) {
+        let now = Instant::now();
+        self.allowlist_logged
+            .retain(|_, logged| now.duration_since(*logged) <= policy.window);
+        let net = self.attempt_net(ip);
+        if let Entry::Vacant(entry) = self.allowlist_logged.entry(net) {
+            entry.insert(now);
+            self.log(&format!(
+                "[ALLOWLIST] IP {ip} is allowlisted, not logging its matches for {}s ({event})",
+                policy.window.as_secs()
+            ));
         }
     }
 
-    pub fn is_blocked(&mut self, ip: &str) -> bool {
-        if let Some(&unblock_time) = self.blocklist.get(ip) {
-            if Instant::now() >= unblock_time {
-                self.blocklist.remove(ip);
-                self.unban_ip(ip);
+    fn check_ban(&mut self, ban: &Ban) -> bool {
+        if let Some(&unblock_time) = self.blocklist.get(ban) {
+            if unblock_time.is_some_and(|unblock_time| Instant::now() >= unblock_time) {
+                self.unblock(ban);
                 self.save_blocklist();
 
                 false
@@ -83,55 +244,215 @@ impl GuardTracker {
         }
     }
 
-    pub fn register_attempt(&mut self, ip: &str) {
+    // IPv6 clients can rotate through the addresses of their network,
+    // so they are tracked and banned by network
+    fn attempt_net(&self, ip: IpAddr) -> IpNet {
+        match ip {
+            IpAddr::V4(_) => IpNet::host(ip),
+            IpAddr::V6(_) => IpNet::new(ip, self.ipv6_prefix).unwrap_or(IpNet::host(ip)),
+        }
+    }
+
+    /// Whether the IP address or its tracked network overlaps the allowlist
+    pub fn is_allowlisted(&self, ip: IpAddr) -> bool {
+        self.allowlist.overlaps(&self.attempt_net(ip))
+    }
+
+    /// Registers a bad attempt of the given weight, bans are limited to the given ports if any.
+    /// The event describes the attempt in the reason of the ban. Allowlisted IPs are skipped,
+    /// with a log line once per window of the policy
+    pub fn register_attempt(
+        &mut self,
+        ip: IpAddr,
+        ports: Option<&PortScope>,
+        policy: &Policy,
+        weight: u64,
+        event: &str,
+    ) {
+        if self.is_allowlisted(ip) {
+            return self.log_allowlisted(ip, policy, event);
+        }
+        let ban = Ban::new(self.attempt_net(ip), ports.cloned());
+        let key = (ban.clone(), policy.clone());
+
+        let events = self.events.entry(key.clone()).or_default();
+        events.push_back(event.to_string());
+        if events.len() > EVENTS_PER_BAN {
+            events.pop_front();
+        }
+
         let now = Instant::now();
-        let queue = self.attempts.entry(ip.to_string()).or_default();
+        let queue = self.attempts.entry(key.clone()).or_default();
 
-        queue.push_back(now);
+        queue.push_back((now, weight));
 
         // Cleanup
-        while let Some(&front) = queue.front() {
-            if now.duration_since(front) > self.window {
+        while let Some(&(front, _)) = queue.front() {
+            if now.duration_since(front) > policy.window {
                 queue.pop_front();
             } else {
                 break;
             }
         }
 
-        if queue.len() >= self.threshold as usize {
-            self.log(&format!("[BLOCKED] IP {ip} exceeded attempt threshold"));
-            self.blocklist
-                .insert(ip.to_string(), now + self.block_duration);
-            self.attempts.remove(ip);
-            self.ban_ip(ip);
+        let score = queue
+            .iter()
+            .fold(0u64, |score, (_, weight)| score.saturating_add(*weight));
+
+        if score >= policy.threshold {
+            self.attempts.remove(&key);
+            let events = self.events.remove(&key).unwrap_or_default();
+
+            self.block(
+                &ban,
+                now,
+                policy.block_duration,
+                "exceeded attempt threshold",
+                Vec::from(events).join("; "),
+            );
+            self.aggregate(&ban, now, policy.block_duration);
             self.save_blocklist();
+            self.save_history();
+        }
+    }
+
+    fn block(
+        &mut self,
+        ban: &Ban,
+        now: Instant,
+        block_duration: Duration,
+        summary: &str,
+        reason: String,
+    ) {
+        let count = self.history.entry(ban.net).or_default();
+        *count += 1;
+        let count = *count;
+        // Permanent if the end of the ban is too far in the future for an Instant
+        let unblock_time = self
+            .recidive
+            .duration(block_duration, count)
+            .and_then(|duration| now.checked_add(duration));
+        let duration = unblock_time.map(|unblock_time| unblock_time - now);
+
+        self.log(&format!(
+            "[BLOCKED] IP {ban} {summary}, ban #{count} {}: {reason}",
+            match duration {
+                Some(duration) => format!("for {}s", duration.as_secs()),
+                None => "permanently".to_string(),
+            }
+        ));
+        self.blocklist.insert(ban.clone(), unblock_time);
+        self.reasons.insert(ban.clone(), reason);
+        self.ban_ip(ban, duration);
+    }
+
+    // Replaces the bans of a network's addresses with one network ban
+    // once enough of them were banned within the window
+    fn aggregate(&mut self, ban: &Ban, now: Instant, block_duration: Duration) {
+        if self.subnet.threshold == 0 {
+            return;
+        }
+
+        let Some(net) = self.subnet.network(ban.net) else {
+            return;
+        };
+        let range = Ban::new(net, ban.ports.clone());
+
+        if self.blocklist.contains_key(&range) || self.allowlist.overlaps(&net) {
+            return;
+        }
+
+        let window = self.subnet.window;
+        let recent = self.recent_bans.entry(range.clone()).or_default();
+        recent.retain(|(member, banned_at)| {
+            member != ban && now.duration_since(*banned_at) <= window
+        });
+        recent.push_back((ban.clone(), now));
+
+        if recent.len() < self.subnet.threshold as usize {
+            return;
+        }
+
+        let recent = self.recent_bans.remove(&range).unwrap_or_default();
+        self.log(&format!(
+            "[SUBNET] {} IPs from {net} were banned within {}s",
+            recent.len(),
+            window.as_secs()
+        ));
+
+        let mut members = Vec::new();
+        for (member, _) in recent {
+            if let Some(unblock_time) = self.blocklist.remove(&member) {
+                self.unban_ip(&member);
+                members.push((member, unblock_time));
+            }
+        }
+
+        let addresses: Vec<String> = members
+            .iter()
+            .map(|(member, _)| member.to_string())
+            .collect();
+        self.block(
+            &range,
+            now,
+            block_duration,
+            "replaces bans of its addresses",
+            addresses.join(", "),
+        );
+        self.aggregated.insert(range, members);
+    }
+
+    fn unblock(&mut self, ban: &Ban) {
+        self.blocklist.remove(ban);
+        self.reasons.remove(ban);
+        self.log(&format!("Unbanned IP {ban}"));
+        self.unban_ip(ban);
+
+        // Addresses of an expired network ban which are still banned on their own
+        let now = Instant::now();
+        for (member, unblock_time) in self.aggregated.remove(ban).unwrap_or_default() {
+            if unblock_time.is_none_or(|unblock_time| unblock_time > now) {
+                self.log(&format!("[SUBNET] Restoring ban of IP {member}"));
+                self.blocklist.insert(member.clone(), unblock_time);
+                self.ban_ip(
+                    &member,
+                    unblock_time.map(|t| t.saturating_duration_since(now)),
+                );
+            } else {
+                self.reasons.remove(&member);
+            }
         }
     }
 
     pub fn cleanup(&mut self) {
         let now = Instant::now();
 
-        self.attempts.retain(|_, queue| {
-            queue.retain(|&instant| now.duration_since(instant) <= self.window);
+        self.attempts.retain(|(_, policy), queue| {
+            queue.retain(|&(instant, _)| now.duration_since(instant) <= policy.window);
             !queue.is_empty()
         });
 
-        let unblocked_ips: Vec<String> = self
+        self.events.retain(|key, _| self.attempts.contains_key(key));
+
+        self.recent_bans.retain(|_, recent| {
+            recent.retain(|(_, banned_at)| now.duration_since(*banned_at) <= self.subnet.window);
+            !recent.is_empty()
+        });
+
+        let unblocked: Vec<Ban> = self
             .blocklist
             .iter()
-            .filter_map(|(ip, &unblock_time)| {
-                if now >= unblock_time {
-                    Some(ip.clone())
+            .filter_map(|(ban, &unblock_time)| {
+                if unblock_time.is_some_and(|unblock_time| now >= unblock_time) {
+                    Some(ban.clone())
                 } else {
                     None
                 }
             })
             .collect();
 
-        for ip in &unblocked_ips {
-            self.blocklist.remove(ip);
-            self.log(&format!("Unbanned IP {ip}"));
-            self.unban_ip(ip);
+        for ban in &unblocked {
+            self.unblock(ban);
         }
     }
 
@@ -146,7 +467,18 @@ impl GuardTracker {
         let file = File::create(&self.banned_ip_path).expect("Failed to create blocklist file");
         let mut writer = BufWriter::new(file);
 
-        for (ip, &unblock_instant) in &self.blocklist {
+        for (ban, &unblock_instant) in &self.blocklist {
+            let comment = match self.reasons.get(ban) {
+                Some(reason) if !reason.is_empty() => format!(" # {reason}"),
+                _ => String::new(),
+            };
+
+            let Some(unblock_instant) = unblock_instant else {
+                writeln!(writer, "{ban}=permanent{comment}")
+                    .expect("Failed to write to blocklist file");
+                continue;
+            };
+
             let remaining = unblock_instant.duration_since(now);
             let unblock_time = sys_now
                 .checked_add(remaining)
@@ -156,8 +488,9 @@ impl GuardTracker {
                 .unwrap_or_default()
                 .as_secs();
 
-            // ip=timestamp
-            writeln!(writer, "{ip}={unblock_timestamp}")
+            // ip=timestamp or ip@ports=timestamp, timestamp is "permanent" for permanent bans,
+            // followed by "# reason"
+            writeln!(writer, "{ban}={unblock_timestamp}{comment}")
                 .expect("Failed to write to blocklist file");
         }
     }
@@ -181,21 +514,63 @@ impl GuardTracker {
 
         let now_instant = Instant::now();
 
-        for line in reader.lines() {
-            if let Ok(line) = line {
-                if let Some((ip, timestamp_str)) = line.split_once('=') {
-                    let timestamp_str = timestamp_str.trim().trim_matches('"');
-
-                    if let Ok(unblock_ts) = timestamp_str.parse::<u64>() {
-                        if unblock_ts > now {
-                            let remaining = unblock_ts - now;
-                            self.blocklist.insert(
-                                ip.trim().to_string(),
-                                now_instant + Duration::from_secs(remaining),
-                            );
-                        }
-                    }
+        for line in lossy_lines(reader) {
+            let (line, reason) = match line.split_once('#') {
+                Some((line, reason)) => (line, reason.trim()),
+                None => (line.as_str(), ""),
+            };
+
+            if let Some((ban, timestamp_str)) = line.split_once('=') {
+                let timestamp_str = timestamp_str.trim().trim_matches('"');
+
+                let Ok(ban) = ban.parse::<Ban>() else {
+                    continue;
+                };
+
+                if timestamp_str == "permanent" {
+                    self.blocklist.insert(ban.clone(), None);
+                } else if let Ok(unblock_ts) = timestamp_str.parse::<u64>()
+                    && unblock_ts > now
+                {
+                    let remaining = unblock_ts - now;
+                    self.blocklist.insert(
+                        ban.clone(),
+                        now_instant.checked_add(Duration::from_secs(remaining)),
+                    );
+                } else {
+                    continue;
                 }
+                self.reasons.insert(ban, reason.to_string());
+            }
+        }
+    }
+
+    pub fn save_history(&self) {
+        if let Err(e) = make_parent_dir(&self.history_path) {
+            panic!("Failed to create parent directory: {}", e);
+        }
+
+        let file = File::create(&self.history_path).expect("Failed to create ban history file");
+        let mut writer = BufWriter::new(file);
+
+        for (net, count) in &self.history {
+            // ip=count or network=count
+            writeln!(writer, "{net}={count}").expect("Failed to write to ban history file");
+        }
+    }
+
+    pub fn load_history(&mut self) {
+        let file = match File::open(&self.history_path) {
+            Ok(file) => file,
+            Err(_) => return,
+        };
+
+        for line in lossy_lines(BufReader::new(file)) {
+            if let Some((net, count)) = line.split_once('=')
+                && let Ok(net) = net.parse::<IpNet>()
+                && let Ok(count) = count.trim().parse::<u32>()
+            {
+                self.history.insert(net, count);
             }
         }
     }
@@ -222,102 +597,607 @@ impl GuardTracker {
         let timestamp = new_date_str();
 
         let mut full_message = String::new();
-        let _ = write!(&mut full_message, "[{timestamp}] {message}\n");
+        let _ = writeln!(&mut full_message, "[{timestamp}] {message}");
 
         if let Err(e) = writer.write_all(full_message.as_bytes()) {
             eprintln!("Failed to write to log file: {e}");
         }
     }
 
-    fn ban_ip(&self, ip: &str) {
-        match ip.parse::<IpAddr>() {
-            Ok(IpAddr::V4(_)) => self.run_iptables("iptables", "-I", ip),
-            Ok(IpAddr::V6(_)) => self.run_iptables("ip6tables", "-I", ip),
-            Err(e) => self.log(&format!("Invalid IP address '{ip}': {e}")),
+    fn ban_ip(&self, ban: &Ban, duration: Option<Duration>) {
+        match &self.firewall {
+            Some(firewall) => {
+                let result = firewall.ban(ban, duration);
+                self.log_result(result, true, ban);
+            }
+            None => self.log(&format!("[DRY RUN] Would ban IP {ban}")),
         }
     }
 
-    fn unban_ip(&self, ip: &str) {
-        match ip.parse::<IpAddr>() {
-            Ok(IpAddr::V4(_)) => self.run_iptables("iptables", "-D", ip),
-            Ok(IpAddr::V6(_)) => self.run_iptables("ip6tables", "-D", ip),
-            Err(e) => self.log(&format!("Invalid IP address '{ip}': {e}")),
+    fn unban_ip(&self, ban: &Ban) {
+        match &self.firewall {
+            Some(firewall) => {
+                let result = firewall.unban(ban);
+                self.log_result(result, false, ban);
+            }
+            None => self.log(&format!("[DRY RUN] Would unban IP {ban}")),
         }
     }
 
-    fn run_iptables(&self, cmd: &str, action: &str, ip: &str) {
-        let status = Command::new("sudo")
-            .arg(cmd)
-            .arg(action)
-            .arg("ubuntu-guard")
-            .arg("-s")
-            .arg(ip)
-            .arg("-j")
-            .arg("REJECT")
-            .status();
-
-        match status {
-            Ok(s) if s.success() => self.log(&format!(
-                "Successfully {} IP {ip} using {cmd}",
-                if action == "-I" { "banned" } else { "unbanned" },
-            )),
-            Ok(s) => self.log(&format!(
-                "Failed to {} IP {ip}; exit code: {s}",
-                if action == "-I" { "ban" } else { "unban" },
+    fn log_result(&self, result: Result<(), String>, ban: bool, target: &Ban) {
+        let name = self.firewall.as_ref().map_or("none", |f| f.name());
+        match result {
+            Ok(()) => self.log(&format!(
+                "Successfully {} IP {target} using {name}",
+                if ban { "banned" } else { "unbanned" },
             )),
             Err(e) => self.log(&format!(
-                "Error while trying to {} IP {ip}: {e}",
-                if action == "-I" { "ban" } else { "unban" },
+                "Failed to {} IP {target}; {e}",
+                if ban { "ban" } else { "unban" },
             )),
         }
     }
 
+    /// Prepares the firewall and bans blocklisted IP addresses it does not know about,
+    /// e.g. after a reboot flushed the rules
     pub fn prepare_chain(&self) {
-        self.create_and_link_chain("iptables");
-        self.create_and_link_chain("ip6tables");
+        let Some(firewall) = &self.firewall else {
+            return self.log("[DRY RUN] Firewall will not be modified");
+        };
+
+        firewall.prepare(&|msg| self.log(msg));
+
+        let active = match firewall.list() {
+            Ok(active) => active,
+            Err(e) => return self.log(&format!("Failed to list banned IPs: {e}")),
+        };
+
+        let now = Instant::now();
+        for (ban, &unblock_time) in &self.blocklist {
+            if !active.contains(ban) {
+                let remaining = unblock_time.map(|t| t.saturating_duration_since(now));
+                self.ban_ip(ban, remaining);
+            }
+        }
     }
+}
 
-    fn create_and_link_chain(&self, cmd: &str) {
-        let name = "ubuntu-guard";
+#[cfg(test)]
+mod tests {
+    use super::{EVENTS_PER_BAN, GuardTracker, Policy, Recidive, Settings, Subnet};
+    use crate::allowlist::Allowlist;
+    use crate::ban::{Ban, PortScope};
+    use crate::firewall::{Call, RecordingFirewall};
+    use crate::test_path::INSTANT_BAN;
+    use std::{
+        env::temp_dir,
+        fs::{read_to_string, remove_dir_all},
+        net::IpAddr,
+        thread::sleep,
+        time::Duration,
+    };
 
-        let chain_exists = Command::new("sudo")
-            .arg(cmd)
-            .arg("-L")
-            .arg(name)
-            .status()
-            .map(|s| s.success())
-            .unwrap_or(false);
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn ban(s: &str) -> Ban {
+        s.parse().unwrap()
+    }
+
+    fn policy(block_duration: Duration) -> Policy {
+        Policy {
+            threshold: 3,
+            window: Duration::from_secs(60),
+            block_duration,
+        }
+    }
+
+    fn settings(name: &str) -> Settings {
+        let dir = temp_dir().join(format!("ubuntu-guard-{name}-{}", std::process::id()));
+
+        Settings {
+            recidive: Recidive::default(),
+            subnet: Subnet::default(),
+            ipv6_prefix: 64,
+            banned_ip_path: dir.join("banned_ips.txt"),
+            history_path: dir.join("ban_history.txt"),
+            log_path: dir.join("guard.log"),
+        }
+    }
+
+    fn tracker(name: &str) -> (GuardTracker, RecordingFirewall) {
+        let settings = settings(name);
+        let _ = remove_dir_all(settings.log_path.parent().unwrap());
+
+        let firewall = RecordingFirewall::default();
+        let tracker = GuardTracker::new(
+            settings,
+            Some(Box::new(firewall.clone())),
+            Allowlist::default(),
+        );
+
+        (tracker, firewall)
+    }
 
-        if !chain_exists {
-            self.log(&format!("Creating new chain {name} in {cmd}"));
-            if let Err(e) = Command::new("sudo").arg(cmd).arg("-N").arg(name).status() {
-                self.log(&format!("Failed to create chain {name} in {cmd}: {e}"));
+    #[test]
+    fn test_ban_after_threshold() {
+        let (mut tracker, firewall) = tracker("threshold");
+        let policy = policy(Duration::from_secs(3600));
+
+        tracker.register_attempt(ip("1.2.3.4"), None, &policy, 1, "test");
+        tracker.register_attempt(ip("1.2.3.4"), None, &policy, 1, "test");
+        assert!(!tracker.is_blocked(ip("1.2.3.4"), None));
+        assert!(firewall.calls().is_empty());
+
+        tracker.register_attempt(ip("1.2.3.4"), None, &policy, 1, "test");
+        assert!(tracker.is_blocked(ip("1.2.3.4"), None));
+        assert!(!tracker.is_blocked(ip("5.6.7.8"), None));
+        assert_eq!(
+            firewall.calls(),
+            vec![Call::Ban(ban("1.2.3.4"), Some(Duration::from_secs(3600)))]
+        );
+    }
+
+    #[test]
+    fn test_unban_on_expiry() {
+        let (mut tracker, firewall) = tracker("expiry");
+        let policy = policy(Duration::from_millis(10));
+
+        for _ in 0..3 {
+            tracker.register_attempt(ip("2001:db8::1"), None, &policy, 1, "test");
+        }
+        assert_eq!(firewall.banned(), vec![ban("2001:db8::/64")]);
+
+        sleep(Duration::from_millis(20));
+        tracker.cleanup();
+
+        assert!(firewall.banned().is_empty());
+        assert!(!tracker.is_blocked(ip("2001:db8::1"), None));
+        assert_eq!(
+            firewall.calls().last(),
+            Some(&Call::Unban(ban("2001:db8::/64")))
+        );
+    }
+
+    #[test]
+    fn test_prepare_restores_missing_bans() {
+        let (mut tracker, _) = tracker("restore");
+        let policy = policy(Duration::from_secs(3600));
+        for _ in 0..3 {
+            tracker.register_attempt(ip("1.2.3.4"), None, &policy, 1, "test");
+        }
+        tracker.save_blocklist();
+
+        // Same files, fresh firewall without any rules
+        let firewall = RecordingFirewall::default();
+        let mut restored = GuardTracker::new(
+            settings("restore"),
+            Some(Box::new(firewall.clone())),
+            Allowlist::default(),
+        );
+        restored.load_blocklist();
+        restored.prepare_chain();
+
+        assert_eq!(firewall.calls()[0], Call::Prepare);
+        assert_eq!(firewall.banned(), vec![ban("1.2.3.4")]);
+        assert!(restored.is_blocked(ip("1.2.3.4"), None));
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let (tracker, _) = tracker("dry-run");
+        let policy = policy(Duration::from_secs(3600));
+        let mut tracker = GuardTracker {
+            firewall: None,
+            ..tracker
+        };
+
+        tracker.prepare_chain();
+        for _ in 0..3 {
+            tracker.register_attempt(ip("1.2.3.4"), None, &policy, 1, "test");
+        }
+        assert!(tracker.is_blocked(ip("1.2.3.4"), None));
+
+        let log = read_to_string(&tracker.log_path).unwrap();
+        assert!(log.contains("[BLOCKED] IP 1.2.3.4 exceeded attempt threshold"));
+        assert!(log.contains("[DRY RUN] Would ban IP 1.2.3.4"));
+    }
+
+    // Separate from the blocked checks, which depend on timing with short bans
+    #[test]
+    fn test_dry_run_expiry() {
+        let (tracker, _) = tracker("dry-run-expiry");
+        let policy = policy(Duration::from_millis(10));
+        let mut tracker = GuardTracker {
+            firewall: None,
+            ..tracker
+        };
+
+        for _ in 0..3 {
+            tracker.register_attempt(ip("1.2.3.4"), None, &policy, 1, "test");
+        }
+        sleep(Duration::from_millis(20));
+        tracker.cleanup();
+
+        assert!(!tracker.is_blocked(ip("1.2.3.4"), None));
+        let log = read_to_string(&tracker.log_path).unwrap();
+        assert!(log.contains("[DRY RUN] Would unban IP 1.2.3.4"));
+    }
+
+    #[test]
+    fn test_port_scoped_ban() {
+        let (mut tracker, firewall) = tracker("ports");
+        let policy = policy(Duration::from_secs(3600));
+        let ssh: PortScope = "22/tcp".parse().unwrap();
+        let http: PortScope = "80,443/tcp".parse().unwrap();
+
+        // Attempts against different services are counted separately
+        tracker.register_attempt(ip("1.2.3.4"), Some(&ssh), &policy, 1, "test");
+        tracker.register_attempt(ip("1.2.3.4"), Some(&ssh), &policy, 1, "test");
+        tracker.register_attempt(ip("1.2.3.4"), Some(&http), &policy, 1, "test");
+        assert!(firewall.calls().is_empty());
+
+        tracker.register_attempt(ip("1.2.3.4"), Some(&ssh), &policy, 1, "test");
+        assert_eq!(firewall.banned(), vec![ban("1.2.3.4@22/tcp")]);
+        assert!(tracker.is_blocked(ip("1.2.3.4"), Some(&ssh)));
+        assert!(!tracker.is_blocked(ip("1.2.3.4"), Some(&http)));
+        assert!(!tracker.is_blocked(ip("1.2.3.4"), None));
+
+        for _ in 0..3 {
+            tracker.register_attempt(ip("1.2.3.4"), None, &policy, 1, "test");
+        }
+        assert!(tracker.is_blocked(ip("1.2.3.4"), Some(&http)));
+
+        tracker.save_blocklist();
+        let saved = read_to_string(&tracker.banned_ip_path).unwrap();
+        assert!(saved.contains("1.2.3.4@22/tcp="));
+    }
+
+    #[test]
+    fn test_allowlisted_ip_is_not_banned() {
+        let (tracker, firewall) = tracker("allowlist");
+        let policy = policy(Duration::from_secs(3600));
+        let mut tracker = GuardTracker {
+            allowlist: Allowlist::parse("10.0.0.0/8").unwrap(),
+            ..tracker
+        };
+
+        for addr in ["10.1.2.3", "127.0.0.1"] {
+            for _ in 0..3 {
+                tracker.register_attempt(ip(addr), None, &policy, 1, "test");
             }
+            assert!(!tracker.is_blocked(ip(addr), None));
         }
+        assert!(firewall.calls().is_empty());
 
-        let linked = Command::new("sudo")
-            .arg(cmd)
-            .arg("-C")
-            .arg("INPUT")
-            .arg("-j")
-            .arg(name)
-            .status()
-            .map(|s| s.success())
-            .unwrap_or(false);
-
-        if !linked {
-            self.log(&format!("Linking {name} to INPUT in {cmd}"));
-            if let Err(e) = Command::new("sudo")
-                .arg(cmd)
-                .arg("-I")
-                .arg("INPUT")
-                .arg("1") // Position 1 to reject banned IP addresses first
-                .arg("-j")
-                .arg(name)
-                .status()
-            {
-                self.log(&format!("Failed to link {name} to INPUT in {cmd}: {e}"));
+        // Nothing is tracked for allowlisted IPs, matches are logged once per window
+        assert!(tracker.attempts.is_empty());
+        assert!(tracker.events.is_empty());
+        assert!(tracker.reasons.is_empty());
+        assert!(tracker.history.is_empty());
+        let log = read_to_string(&tracker.log_path).unwrap();
+        assert_eq!(
+            log.matches("[ALLOWLIST] IP 10.1.2.3 is allowlisted")
+                .count(),
+            1
+        );
+        assert!(log.contains(
+            "[ALLOWLIST] IP 10.1.2.3 is allowlisted, not logging its matches for 60s (test)"
+        ));
+        assert_eq!(log.matches("[ALLOWLIST]").count(), 2);
+    }
+
+    #[test]
+    fn test_recidive_duration() {
+        let recidive = Recidive {
+            factor: 2.0,
+            max_duration: Duration::from_secs(3600 * 6),
+            permanent_after: 5,
+        };
+        let base = Duration::from_secs(3600);
+
+        let durations: Vec<Option<u64>> = (1..=6)
+            .map(|count| recidive.duration(base, count).map(|d| d.as_secs()))
+            .collect();
+        assert_eq!(
+            durations,
+            vec![
+                Some(3600),
+                Some(7200),
+                Some(14400),
+                Some(21600),
+                Some(21600),
+                None
+            ]
+        );
+
+        let default = Recidive::default();
+        assert_eq!(default.duration(base, 10), Some(base));
+
+        // Overflowing durations are clamped to the maximum, never to the base
+        let unbounded = Recidive {
+            factor: 10.0,
+            ..Recidive::default()
+        };
+        assert_eq!(unbounded.duration(base, 100), Some(Duration::MAX));
+        let capped = Recidive {
+            factor: 1e300,
+            max_duration: Duration::from_secs(7200),
+            permanent_after: 0,
+        };
+        assert_eq!(capped.duration(base, 10), Some(Duration::from_secs(7200)));
+    }
+
+    #[test]
+    fn test_unbounded_ban_is_permanent() {
+        let (tracker, firewall) = tracker("unbounded");
+        let policy = policy(Duration::from_secs(3600));
+        let mut tracker = GuardTracker {
+            recidive: Recidive {
+                factor: 10.0,
+                ..Recidive::default()
+            },
+            history: [(ban("1.2.3.4").net, 99)].into(),
+            ..tracker
+        };
+
+        for _ in 0..3 {
+            tracker.register_attempt(ip("1.2.3.4"), None, &policy, 1, "test");
+        }
+        assert_eq!(tracker.blocklist.get(&ban("1.2.3.4")), Some(&None));
+        assert_eq!(firewall.calls(), vec![Call::Ban(ban("1.2.3.4"), None)]);
+    }
+
+    #[test]
+    fn test_repeat_offender() {
+        let (tracker, firewall) = tracker("recidive");
+        let policy = policy(Duration::from_millis(10));
+        let mut tracker = GuardTracker {
+            recidive: Recidive {
+                factor: 3.0,
+                max_duration: Duration::from_secs(3600),
+                permanent_after: 2,
+            },
+            ..tracker
+        };
+
+        for _ in 0..3 {
+            tracker.register_attempt(ip("1.2.3.4"), None, &policy, 1, "test");
+        }
+        sleep(Duration::from_millis(20));
+        tracker.cleanup();
+        assert!(!tracker.is_blocked(ip("1.2.3.4"), None));
+
+        for _ in 0..3 {
+            tracker.register_attempt(ip("1.2.3.4"), None, &policy, 1, "test");
+        }
+        assert_eq!(
+            firewall.calls().last(),
+            Some(&Call::Ban(ban("1.2.3.4"), Some(Duration::from_millis(30))))
+        );
+
+        // History survives a restart
+        let mut restored = GuardTracker::new(
+            settings("recidive"),
+            Some(Box::new(firewall.clone())),
+            Allowlist::default(),
+        );
+        restored.recidive = tracker.recidive;
+        restored.load_history();
+
+        for _ in 0..3 {
+            restored.register_attempt(ip("1.2.3.4"), None, &policy, 1, "test");
+        }
+        assert_eq!(
+            firewall.calls().last(),
+            Some(&Call::Ban(ban("1.2.3.4"), None))
+        );
+
+        sleep(Duration::from_millis(40));
+        restored.cleanup();
+        assert!(restored.is_blocked(ip("1.2.3.4"), None));
+
+        restored.save_blocklist();
+        let saved = read_to_string(&restored.banned_ip_path).unwrap();
+        assert!(saved.contains("1.2.3.4=permanent"));
+    }
+
+    #[test]
+    fn test_subnet_aggregation() {
+        // Bans of the network and its addresses, the repeat offender 1.2.3.1
+        // gets a longer ban than the network
+        fn ban_subnet(name: &str, block_duration: Duration) -> (GuardTracker, RecordingFirewall) {
+            let (tracker, firewall) = tracker(name);
+            let policy = policy(block_duration);
+            let mut tracker = GuardTracker {
+                subnet: Subnet {
+                    threshold: 3,
+                    ..Subnet::default()
+                },
+                recidive: Recidive {
+                    factor: 10_000.0,
+                    ..Recidive::default()
+                },
+                ..tracker
+            };
+            tracker.history.insert("1.2.3.1".parse().unwrap(), 1);
+
+            for addr in ["1.2.3.1", "1.2.3.2", "1.2.4.1"] {
+                for _ in 0..3 {
+                    tracker.register_attempt(ip(addr), None, &policy, 1, "test");
+                }
+            }
+            assert_eq!(
+                firewall.banned(),
+                vec![ban("1.2.3.1"), ban("1.2.3.2"), ban("1.2.4.1")]
+            );
+
+            for _ in 0..3 {
+                tracker.register_attempt(ip("1.2.3.3"), None, &policy, 1, "test");
+            }
+            assert_eq!(firewall.banned(), vec![ban("1.2.4.1"), ban("1.2.3.0/24")]);
+
+            (tracker, firewall)
+        }
+
+        let (mut tracker, _) = ban_subnet("subnet", Duration::from_secs(3600));
+        assert!(tracker.is_blocked(ip("1.2.3.200"), None));
+        assert!(tracker.is_blocked(ip("1.2.3.2"), None));
+        assert!(!tracker.is_blocked(ip("1.2.5.1"), None));
+
+        // The repeat offender is banned for 500s, the others expire
+        let (mut tracker, firewall) = ban_subnet("subnet-expiry", Duration::from_millis(50));
+        sleep(Duration::from_millis(100));
+        tracker.cleanup();
+
+        assert_eq!(firewall.banned(), vec![ban("1.2.3.1")]);
+        assert!(tracker.is_blocked(ip("1.2.3.1"), None));
+        assert!(!tracker.is_blocked(ip("1.2.3.2"), None));
+    }
+
+    #[test]
+    fn test_subnet_with_allowlisted_address() {
+        let (tracker, firewall) = tracker("subnet-allowlist");
+        let policy = policy(Duration::from_secs(3600));
+        let mut tracker = GuardTracker {
+            subnet: Subnet {
+                threshold: 2,
+                ..Subnet::default()
+            },
+            allowlist: Allowlist::parse("1.2.3.100").unwrap(),
+            ..tracker
+        };
+
+        for addr in ["1.2.3.1", "1.2.3.2"] {
+            for _ in 0..3 {
+                tracker.register_attempt(ip(addr), None, &policy, 1, "test");
             }
         }
+        assert_eq!(firewall.banned(), vec![ban("1.2.3.1"), ban("1.2.3.2")]);
+    }
+
+    #[test]
+    fn test_ipv6_prefix() {
+        let (mut tracker, firewall) = tracker("ipv6");
+        let policy = policy(Duration::from_secs(3600));
+
+        for addr in ["2001:db8:0:1::1", "2001:db8:0:1::2", "2001:db8:0:1:ffff::3"] {
+            tracker.register_attempt(ip(addr), None, &policy, 1, "test");
+        }
+        assert_eq!(firewall.banned(), vec![ban("2001:db8:0:1::/64")]);
+        assert!(tracker.is_blocked(ip("2001:db8:0:1::42"), None));
+        assert!(!tracker.is_blocked(ip("2001:db8:0:2::1"), None));
+
+        // IPv4 stays per address
+        for addr in ["1.2.3.1", "1.2.3.2", "1.2.3.3"] {
+            tracker.register_attempt(ip(addr), None, &policy, 1, "test");
+        }
+        assert_eq!(firewall.banned(), vec![ban("2001:db8:0:1::/64")]);
+    }
+
+    #[test]
+    fn test_policies_are_counted_separately() {
+        let (mut tracker, firewall) = tracker("policies");
+        let ssh = Policy {
+            threshold: 2,
+            window: Duration::from_secs(600),
+            block_duration: Duration::from_secs(86400),
+        };
+        let http = Policy {
+            threshold: 4,
+            ..policy(Duration::from_secs(3600))
+        };
+
+        for _ in 0..3 {
+            tracker.register_attempt(ip("1.2.3.4"), None, &http, 1, "test");
+        }
+        tracker.register_attempt(ip("1.2.3.4"), None, &ssh, 1, "test");
+        assert!(firewall.calls().is_empty());
+
+        tracker.register_attempt(ip("1.2.3.4"), None, &ssh, 1, "test");
+        assert_eq!(
+            firewall.calls(),
+            vec![Call::Ban(ban("1.2.3.4"), Some(Duration::from_secs(86400)))]
+        );
+    }
+
+    #[test]
+    fn test_weighted_attempts() {
+        let (mut tracker, firewall) = tracker("weights");
+        let policy = Policy {
+            threshold: 5,
+            ..policy(Duration::from_secs(3600))
+        };
+
+        tracker.register_attempt(ip("1.2.3.4"), None, &policy, 1, "test");
+        tracker.register_attempt(ip("1.2.3.4"), None, &policy, 3, "test");
+        assert!(firewall.calls().is_empty());
+        tracker.register_attempt(ip("1.2.3.4"), None, &policy, 1, "test");
+        assert_eq!(firewall.banned(), vec![ban("1.2.3.4")]);
+
+        tracker.register_attempt(ip("5.6.7.8"), None, &policy, 1, "test");
+        tracker.register_attempt(ip("5.6.7.8"), None, &policy, INSTANT_BAN, "test");
+        assert_eq!(firewall.banned(), vec![ban("1.2.3.4"), ban("5.6.7.8")]);
+    }
+
+    #[test]
+    fn test_ban_reason() {
+        let (mut tracker, _) = tracker("reason");
+        let policy = policy(Duration::from_secs(3600));
+
+        for path in ["/.env", "/.git/config", "/admin", "/wp-login.php"] {
+            tracker.register_attempt(ip("1.2.3.4"), None, &policy, 1, path);
+        }
+        for path in ["/a.php", "/b.php", "/c.php"] {
+            tracker.register_attempt(ip("5.6.7.8"), None, &policy, 1, path);
+        }
+
+        let log = read_to_string(&tracker.log_path).unwrap();
+        assert!(log.contains("[BLOCKED] IP 1.2.3.4 exceeded attempt threshold, ban #1 for 3600s: /.env; /.git/config; /admin"));
+
+        tracker.save_blocklist();
+        let saved = read_to_string(&tracker.banned_ip_path).unwrap();
+        assert!(saved.contains(" # /a.php; /b.php; /c.php\n"));
+
+        // Reasons survive a restart and old lines without one still load
+        std::fs::write(
+            &tracker.banned_ip_path,
+            format!("{saved}9.9.9.9=permanent\n"),
+        )
+        .unwrap();
+        let mut restored = GuardTracker::new(settings("reason"), None, Allowlist::default());
+        restored.load_blocklist();
+        assert!(restored.is_blocked(ip("9.9.9.9"), None));
+        assert_eq!(
+            restored.reasons[&ban("1.2.3.4")],
+            "/.env; /.git/config; /admin"
+        );
+    }
+
+    #[test]
+    fn test_ban_reason_per_policy() {
+        let (mut tracker, _) = tracker("reason-policy");
+        let ssh_ports: PortScope = "22/tcp".parse().unwrap();
+        let http_ports: PortScope = "80,443/tcp".parse().unwrap();
+        let ssh = Policy {
+            threshold: EVENTS_PER_BAN as u64 + 2,
+            ..policy(Duration::from_secs(3600))
+        };
+        let http = Policy {
+            threshold: 10,
+            ..ssh.clone()
+        };
+
+        tracker.register_attempt(ip("1.2.3.4"), Some(&http_ports), &http, 1, "/.env");
+        for i in 0..ssh.threshold {
+            tracker.register_attempt(ip("1.2.3.4"), Some(&ssh_ports), &ssh, 1, &i.to_string());
+        }
+
+        // Only the last events of the SSH attempts
+        let reason = &tracker.reasons[&ban("1.2.3.4@22/tcp")];
+        assert_eq!(reason, "2; 3; 4; 5; 6");
+        assert_eq!(reason.split("; ").count(), EVENTS_PER_BAN);
+        assert_eq!(tracker.events.len(), 1);
     }
 }
@@ -1,30 +1,57 @@
 use notify::{Config, EventKind, RecommendedWatcher, RecursiveMode, Result, Watcher};
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     env,
     fs::read_dir,
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
     thread::{sleep, spawn},
     time::Duration,
 };
 
+mod allowlist;
+mod ban;
+mod config;
+mod firewall;
+mod glob;
 mod guard;
+mod net;
 mod parse_logs;
 mod reader;
 mod test_path;
 mod test_regex;
 
-fn parse_env<T: std::str::FromStr>(name: &str) -> T {
-    env::var(name)
-        .unwrap_or_else(|_| panic!("{name} not set"))
-        .parse()
-        .unwrap_or_else(|_| panic!("{name} must be a valid value"))
-}
-
 struct LogSource {
     kind: LogKind,
     path: PathBuf,
+    // None when bans block every port
+    ports: Option<ban::PortScope>,
+    policy: guard::Policy,
+    // Paths of the source which are never bad
+    ignores: Vec<test_path::Rule>,
+}
+
+/// Files of a source in a watched directory, matched by name
+struct FilePattern {
+    pattern: String,
+    kind: LogKind,
+    ports: Option<ban::PortScope>,
+    policy: guard::Policy,
+    ignores: Vec<test_path::Rule>,
+}
+
+impl FilePattern {
+    fn source(&self, path: &Path) -> Option<LogSource> {
+        let name = path.file_name()?.to_str()?;
+
+        (path.is_file() && glob::matches(&self.pattern, name)).then(|| LogSource {
+            kind: self.kind,
+            path: path.into(),
+            ports: self.ports.clone(),
+            policy: self.policy.clone(),
+            ignores: self.ignores.clone(),
+        })
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -34,6 +61,29 @@ enum LogKind {
     Ssh,
 }
 
+impl LogKind {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Apache => "apache",
+            Self::Nginx => "nginx",
+            Self::Ssh => "ssh",
+        }
+    }
+}
+
+impl std::str::FromStr for LogKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "apache" => Ok(Self::Apache),
+            "nginx" => Ok(Self::Nginx),
+            "ssh" => Ok(Self::Ssh),
+            other => Err(format!("Unknown log kind '{other}'")),
+        }
+    }
+}
+
 impl LogSource {
     fn from_path(path: &str) -> Self {
         let kind = if path == "/var/log/auth.log" {
@@ -47,6 +97,9 @@ impl LogSource {
         LogSource {
             kind,
             path: path.into(),
+            ports: None,
+            policy: guard::Policy::default(),
+            ignores: Vec::new(),
         }
     }
 
@@ -66,11 +119,16 @@ impl LogSource {
         }
     }
 
-    fn is_bad(&self, msg: &str) -> bool {
-        match self.kind {
-            LogKind::Apache | LogKind::Nginx => test_path::is_bad_path(msg),
-            // If LogSource returns Some(Log<'_>), it is always bad attempt
-            LogKind::Ssh => true,
+    /// Rule matched by a bad attempt, None if the line is not bad
+    fn detect(&self, log: &parse_logs::Log) -> Option<test_path::RuleMatch> {
+        match log {
+            parse_logs::Log::Apache(request) | parse_logs::Log::Nginx(request) => {
+                test_path::request_match(request, &self.ignores)
+            }
+            parse_logs::Log::Malformed {
+                request, status, ..
+            } => test_path::malformed_match(request, *status, &self.ignores),
+            parse_logs::Log::Ssh { msg, .. } => test_path::ssh_match(msg, &self.ignores),
         }
     }
 }
@@ -80,11 +138,13 @@ fn main() {
     // ./guard test /var/log/apache2/access.log
     // ./guard test /var/log/apache2/access.log --print-all-matched
     // ./guard test /var/log/apache2/access.log --print-all-missed
+    // ./guard test /srv/www/shop/logs/access.log --kind nginx
+    // ./guard test /var/log/nginx/access.log --rules /etc/ubuntu-guard/http.rules
     let args: Vec<String> = env::args().collect();
     if args.len() > 1 && args[1] == "test" {
         if args.len() < 3 {
             eprintln!(
-                "Usage: {} test <log_path> [--print-all-matched] [--print-all-missed]",
+                "Usage: {} test <log_path> [--kind <apache|nginx|ssh>] [--rules <rules_path>] [--print-all-matched] [--print-all-missed]",
                 args[0]
             );
             std::process::exit(1);
@@ -93,8 +153,24 @@ fn main() {
         let log_path = &args[2];
         let print_matched = args.contains(&"--print-all-matched".to_string());
         let print_missed = args.contains(&"--print-all-missed".to_string());
+        let kind = args.iter().position(|arg| arg == "--kind").map(|i| {
+            match args.get(i + 1).map(|kind| kind.parse()) {
+                Some(Ok(kind)) => kind,
+                Some(Err(e)) => panic!("{e}"),
+                None => panic!("--kind requires a value"),
+            }
+        });
+
+        // Only the rules of the file, to check it on its own
+        if let Some(i) = args.iter().position(|arg| arg == "--rules") {
+            let path = args.get(i + 1).expect("--rules requires a value");
+            match test_path::RuleSet::load(path.as_ref(), false) {
+                Ok(rules) => test_path::set_rules(rules),
+                Err(e) => panic!("Invalid rules: {e}"),
+            }
+        }
 
-        test_regex::test(log_path, print_matched, print_missed);
+        test_regex::test(log_path, kind, print_matched, print_missed);
         return;
     }
 
@@ -102,24 +178,52 @@ fn main() {
     // ./guard
     dotenvy::dotenv().ok();
 
-    let threshold: u64 = parse_env("THRESHOLD");
-    let window: u64 = parse_env("WINDOW_SECONDS");
-    let block_duration: u64 = parse_env("BLOCK_DURATION_SECONDS");
+    let config_path = env::var("CONFIG_PATH").unwrap_or(config::DEFAULT_PATH.to_string());
+    let config = config::Config::load(config_path.as_ref(), &|name| env::var(name).ok())
+        .unwrap_or_else(|e| {
+            eprintln!("Invalid configuration: {e}");
+            std::process::exit(1);
+        });
 
-    let guard_banned_ip_path: String = parse_env("GUARD_BANNED_IP_PATH");
-    let guard_log_path: String = parse_env("GUARD_LOG_PATH");
+    if let Some(path) = &config.rules_path {
+        match test_path::RuleSet::load(path, config.default_rules) {
+            Ok(rules) => {
+                println!("Loaded {} rules from {:?}", rules.len(), path);
+                test_path::set_rules(rules);
+            }
+            Err(e) => {
+                eprintln!("Invalid rules: {e}");
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let firewall = config
+        .backend
+        .into_firewall(config.action)
+        .unwrap_or_else(|e| {
+            eprintln!("Invalid firewall configuration: {e}");
+            std::process::exit(1);
+        });
+
+    let mut allowlist = config.allowlist;
+    if config.allow_local_addresses
+        && let Err(e) = allowlist.add_local_addresses()
+    {
+        println!("Failed to allowlist local addresses: {e}");
+    }
+    println!("Allowlisted {} addresses and networks", allowlist.len());
 
     let tracker = Arc::new(Mutex::new(guard::GuardTracker::new(
-        threshold,
-        Duration::from_secs(window),
-        Duration::from_secs(block_duration),
-        guard_banned_ip_path,
-        guard_log_path,
+        config.settings,
+        (!config.dry_run).then_some(firewall),
+        allowlist,
     )));
 
     {
         let mut guard = tracker.lock().unwrap();
         guard.load_blocklist();
+        guard.load_history();
         guard.prepare_chain();
     }
 
@@ -130,128 +234,167 @@ fn main() {
                 let mut tracker = tracker_clone.lock().unwrap();
                 tracker.cleanup();
                 tracker.save_blocklist();
+
+                let invalid_ips = parse_logs::take_invalid_ips();
+                if invalid_ips > 0 {
+                    tracker.log(&format!(
+                        "Ignored {invalid_ips} lines with an invalid IP address"
+                    ));
+                }
             }
             sleep(Duration::from_secs(60));
         }
     });
 
-    // Directories to watch
-    let dirs_to_watch: Vec<(PathBuf, LogKind)> = vec![
-        ("/var/log".into(), LogKind::Ssh),
-        ("/var/log/apache2".into(), LogKind::Apache),
-        ("/var/log/nginx".into(), LogKind::Nginx),
-    ];
-
-    let mut watchers = Vec::new();
+    // Watched directories with the file name patterns of the sources in them
+    let mut dirs: BTreeMap<PathBuf, Vec<FilePattern>> = BTreeMap::new();
+    for source in &config.sources {
+        for path in &source.paths {
+            let path = Path::new(path);
+            let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+                continue;
+            };
+
+            let matched_dirs = glob::expand_dirs(dir);
+            if matched_dirs.is_empty() {
+                println!("Skipping {:?}: directory does not exist", path);
+            }
 
-    for (dir_path, kind) in dirs_to_watch {
-        if !dir_path.exists() {
-            println!("Skipping {:?}: directory does not exist", dir_path);
-            continue;
+            for dir in matched_dirs {
+                dirs.entry(dir).or_default().push(FilePattern {
+                    pattern: name.to_string_lossy().to_string(),
+                    kind: source.kind,
+                    ports: source.ports.clone(),
+                    policy: source.policy.clone(),
+                    ignores: source.ignores.clone(),
+                });
+            }
         }
+    }
+
+    let mut watchers = Vec::new();
+    if let Some(path) = config.rules_path {
+        watchers.push(watch_rules(path, config.default_rules, tracker.clone()));
+    }
 
+    for (dir_path, patterns) in dirs {
         // Collect relevant log files for this directory
         let mut log_sources = Vec::new();
-        match kind {
-            LogKind::Ssh => {
-                let auth_log = dir_path.join("auth.log");
-                if auth_log.exists() {
-                    log_sources.push(LogSource {
-                        kind,
-                        path: auth_log,
-                    });
-                }
-            }
-            LogKind::Apache => {
-                if let Ok(entries) = read_dir(&dir_path) {
-                    for entry in entries.flatten() {
-                        let path = entry.path();
-                        if let Some(name) = path.file_name().and_then(|f| f.to_str()) {
-                            if name.ends_with("access.log") {
-                                log_sources.push(LogSource { kind, path });
-                            }
-                        }
-                    }
-                }
-            }
-            LogKind::Nginx => {
-                if let Ok(entries) = read_dir(&dir_path) {
-                    for entry in entries.flatten() {
-                        let path = entry.path();
-                        if let Some(name) = path.file_name().and_then(|f| f.to_str()) {
-                            if name.ends_with("access.log") {
-                                log_sources.push(LogSource { kind, path });
-                            }
-                        }
-                    }
+        if let Ok(entries) = read_dir(&dir_path) {
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if let Some(src) = patterns.iter().find_map(|pattern| pattern.source(&path)) {
+                    log_sources.push(src);
                 }
             }
         }
 
+        // Still watched for log files created later
         if log_sources.is_empty() {
             println!("No log files found in {:?}", dir_path);
-            continue;
         }
 
-        let mut tail_readers: HashMap<String, reader::TailReader> = HashMap::new();
-        let mut sources_map: HashMap<String, LogSource> = HashMap::new();
+        let mut tail_readers: HashMap<PathBuf, reader::TailReader> = HashMap::new();
+        let mut sources_map: HashMap<PathBuf, LogSource> = HashMap::new();
 
         for src in log_sources {
-            let name = src.path.file_name().unwrap().to_string_lossy().to_string();
             let reader =
                 reader::TailReader::new(src.path.clone()).expect("Failed to initialize TailReader");
-            tail_readers.insert(name.clone(), reader);
-            sources_map.insert(name, src);
+            tail_readers.insert(src.path.clone(), reader);
+            sources_map.insert(src.path.clone(), src);
         }
 
-        let watched_files: Vec<String> = sources_map.keys().cloned().collect();
+        let mut watched_files: Vec<String> = sources_map
+            .keys()
+            .filter_map(|path| path.file_name())
+            .map(|name| name.to_string_lossy().to_string())
+            .collect();
+        watched_files.sort();
 
         let tracker_clone = tracker.clone();
         let log_dir_clone = dir_path.clone();
 
         let mut watcher = RecommendedWatcher::new(
             move |res: Result<notify::Event>| {
-                if let Ok(event) = res {
-                    if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
-                        for path in &event.paths {
-                            let fname = match path.file_name().and_then(|f| f.to_str()) {
-                                Some(name) => name,
-                                None => continue,
-                            };
+                let Ok(event) = res else {
+                    return;
+                };
 
-                            let source = match sources_map.get(fname) {
-                                Some(src) => src,
-                                None => continue,
-                            };
-
-                            let reader = match tail_readers.get(fname) {
-                                Some(r) => r,
-                                None => continue,
+                match event.kind {
+                    // New log files are read from the start
+                    EventKind::Create(_) => {
+                        for path in &event.paths {
+                            if sources_map.contains_key(path) {
+                                continue;
+                            }
+                            let Some(src) =
+                                patterns.iter().find_map(|pattern| pattern.source(path))
+                            else {
+                                continue;
                             };
 
-                            for line in reader.read_new_lines() {
-                                let mut tracker = tracker_clone.lock().unwrap();
-
-                                if let Some(parsed) = source.parse(&line) {
-                                    let ip = parsed.ip();
-                                    let msg = parsed.message();
+                            let tracker = tracker_clone.lock().unwrap();
+                            match reader::TailReader::from_start(path.clone()) {
+                                Ok(reader) => {
+                                    tracker.log(&format!("Watching new log file {:?}", path));
+                                    tail_readers.insert(path.clone(), reader);
+                                    sources_map.insert(path.clone(), src);
+                                }
+                                Err(e) => tracker.log(&format!("Failed to open {:?}: {e}", path)),
+                            }
+                        }
+                    }
+                    EventKind::Remove(_) => {
+                        for path in &event.paths {
+                            if sources_map.remove(path).is_some() {
+                                tail_readers.remove(path);
+                                tracker_clone
+                                    .lock()
+                                    .unwrap()
+                                    .log(&format!("Stopped watching removed log file {:?}", path));
+                            }
+                        }
+                    }
+                    _ => {}
+                }
 
-                                    if !tracker.is_blocked(ip) && source.is_bad(msg) {
+                if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                    for path in &event.paths {
+                        let source = match sources_map.get(path) {
+                            Some(src) => src,
+                            None => continue,
+                        };
+
+                        let reader = match tail_readers.get(path) {
+                            Some(r) => r,
+                            None => continue,
+                        };
+
+                        for line in reader.read_new_lines() {
+                            let mut tracker = tracker_clone.lock().unwrap();
+
+                            if let Some(parsed) = source.parse(&line) {
+                                let ip = parsed.ip();
+                                let msg = parsed.message();
+
+                                if !tracker.is_blocked(ip, source.ports.as_ref())
+                                    && let Some(matched) = source.detect(&parsed)
+                                {
+                                    let event = format!("{}: {msg}", matched.rule);
+                                    if !tracker.is_allowlisted(ip) {
                                         tracker.log(&format!(
-                                            "[{}] Registering IP {ip}",
-                                            source.prefix(),
+                                            "[{}] Registering IP {ip} ({event})",
+                                            source.prefix()
                                         ));
-                                        tracker.register_attempt(ip);
                                     }
+                                    tracker.register_attempt(
+                                        ip,
+                                        source.ports.as_ref(),
+                                        &source.policy,
+                                        matched.weight,
+                                        &event,
+                                    );
                                 }
-                                // else {
-                                //     // Parsing error
-                                //     tracker.log(&format!(
-                                //         "[{}] Failed to parse line: {}",
-                                //         source.prefix(),
-                                //         line
-                                //     ));
-                                // }
                             }
                         }
                     }
@@ -276,3 +419,49 @@ fn main() {
 
     std::thread::park();
 }
+
+/// Reloads the rules when the file changes, the current rules are kept if it is invalid
+fn watch_rules(
+    path: PathBuf,
+    with_defaults: bool,
+    tracker: Arc<Mutex<guard::GuardTracker>>,
+) -> RecommendedWatcher {
+    let rules_path = path.clone();
+    let mut watcher = RecommendedWatcher::new(
+        move |res: Result<notify::Event>| {
+            let Ok(event) = res else {
+                return;
+            };
+            if !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_))
+                || !event.paths.contains(&rules_path)
+            {
+                return;
+            }
+
+            let tracker = tracker.lock().unwrap();
+            match test_path::RuleSet::load(&rules_path, with_defaults) {
+                Ok(rules) => {
+                    tracker.log(&format!(
+                        "Reloaded {} rules from {:?}",
+                        rules.len(),
+                        rules_path
+                    ));
+                    test_path::set_rules(rules);
+                }
+                Err(e) => tracker.log(&format!("Keeping the current rules: {e}")),
+            }
+        },
+        Config::default()
+            .with_poll_interval(Duration::from_secs(1))
+            .with_compare_contents(false),
+    )
+    .expect("Failed to create watcher");
+
+    // The directory is watched, editors replace the file instead of writing to it
+    let dir = path.parent().unwrap_or(Path::new("/"));
+    watcher
+        .watch(dir, RecursiveMode::NonRecursive)
+        .expect("Failed to watch rules directory");
+
+    watcher
+}
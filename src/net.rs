@@ -0,0 +1,185 @@
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+/// IPv4 or IPv6 network in CIDR notation, the address is always the network address.
+/// Single addresses are displayed without a prefix length
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Self, String> {
+        let max = max_prefix(addr);
+        if prefix > max {
+            return Err(format!("Prefix /{prefix} is too long for {addr}"));
+        }
+
+        let addr = match addr {
+            IpAddr::V4(v4) => {
+                let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+                IpAddr::V4(Ipv4Addr::from(u32::from(v4) & mask))
+            }
+            IpAddr::V6(v6) => {
+                let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask))
+            }
+        };
+
+        Ok(Self { addr, prefix })
+    }
+
+    /// Network containing only the given address
+    pub fn host(addr: IpAddr) -> Self {
+        Self {
+            addr,
+            prefix: max_prefix(addr),
+        }
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    pub fn is_host(&self) -> bool {
+        self.prefix == max_prefix(self.addr)
+    }
+
+    /// Whether the networks share any address
+    pub fn overlaps(&self, other: &IpNet) -> bool {
+        self.contains(other.addr) || other.contains(self.addr)
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(_), IpAddr::V4(_)) | (IpAddr::V6(_), IpAddr::V6(_)) => {
+                Self::new(ip, self.prefix).is_ok_and(|net| net.addr == self.addr)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn max_prefix(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_host() {
+            write!(f, "{}", self.addr)
+        } else {
+            write!(f, "{}/{}", self.addr, self.prefix)
+        }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    // 1.2.3.4, 10.0.0.0/8 or 2001:db8::/32
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|e| format!("Invalid IP address '{addr}': {e}"))?;
+
+        match prefix {
+            Some(prefix) => {
+                let prefix = prefix
+                    .parse()
+                    .map_err(|_| format!("Invalid prefix length '{prefix}'"))?;
+                Self::new(addr, prefix)
+            }
+            None => Ok(Self::host(addr)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IpNet;
+    use std::net::IpAddr;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_net() {
+        let cases = [
+            ("1.2.3.4", "1.2.3.4"),
+            ("1.2.3.4/32", "1.2.3.4"),
+            ("10.1.2.3/8", "10.0.0.0/8"),
+            ("0.0.0.0/0", "0.0.0.0/0"),
+            ("2001:db8::1", "2001:db8::1"),
+            ("2001:db8:1:2:3::1/64", "2001:db8:1:2::/64"),
+        ];
+
+        for (s, expected) in cases {
+            let net: IpNet = s.parse().unwrap();
+            assert_eq!(net.to_string(), expected, "Network that failed: {s}");
+        }
+
+        for s in [
+            "",
+            "1.2.3.4/33",
+            "2001:db8::/129",
+            "1.2.3.4/",
+            "example.com",
+        ] {
+            assert!(s.parse::<IpNet>().is_err(), "Should not parse {s}");
+        }
+    }
+
+    #[test]
+    fn test_contains() {
+        let net: IpNet = "192.168.0.0/16".parse().unwrap();
+        assert!(net.contains(ip("192.168.10.1")));
+        assert!(!net.contains(ip("192.169.0.1")));
+        assert!(!net.contains(ip("::ffff:192.168.10.1")));
+
+        let net: IpNet = "2001:db8::/32".parse().unwrap();
+        assert!(net.contains(ip("2001:db8:ffff::1")));
+        assert!(!net.contains(ip("2001:db9::1")));
+
+        let any: IpNet = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains(ip("8.8.8.8")));
+    }
+
+    #[test]
+    fn test_overlaps() {
+        let net: IpNet = "10.1.0.0/16".parse().unwrap();
+        let overlapping = ["10.0.0.0/8", "10.1.2.0/24", "10.1.2.3"];
+        let separate = ["10.2.0.0/16", "11.0.0.0/8", "::/0"];
+
+        for other in overlapping {
+            assert!(
+                net.overlaps(&other.parse().unwrap()),
+                "Should overlap {other}"
+            );
+        }
+        for other in separate {
+            assert!(
+                !net.overlaps(&other.parse().unwrap()),
+                "Should not overlap {other}"
+            );
+        }
+    }
+}
@@ -1,143 +1,388 @@
+use std::{
+    net::IpAddr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+// Lines rejected because of an unparsable IP address, e.g. a hostname from UseDNS
+static INVALID_IPS: AtomicU64 = AtomicU64::new(0);
+
+/// Number of lines rejected for an invalid IP address since the last call
+pub fn take_invalid_ips() -> u64 {
+    INVALID_IPS.swap(0, Ordering::Relaxed)
+}
+
+// IPv4-mapped IPv6 addresses are normalised to IPv4
+fn parse_ip(ip: &str) -> Option<IpAddr> {
+    match ip.parse::<IpAddr>() {
+        Ok(ip) => Some(ip.to_canonical()),
+        Err(_) => {
+            INVALID_IPS.fetch_add(1, Ordering::Relaxed);
+            None
+        }
+    }
+}
+
+/// Request of an access log line, the fields after the request line are None
+/// when missing, e.g. in the common log format
+#[derive(Debug, PartialEq)]
+pub struct Request<'a> {
+    pub ip: IpAddr,
+    pub method: &'a str,
+    /// Request target as logged, e.g. `/index.php?id=1`
+    pub target: &'a str,
+    pub path: &'a str,
+    /// After the first `?`, None without one
+    pub query: Option<&'a str>,
+    pub status: Option<u16>,
+    /// Size of the response body, 0 when logged as `-`
+    pub bytes: Option<u64>,
+    /// As logged, `-` when the client sent none and escaped quotes as `\"`
+    pub referer: Option<&'a str>,
+    pub user_agent: Option<&'a str>,
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Log<'a> {
-    Apache { ip: &'a str, path: &'a str },
-    Nginx { ip: &'a str, path: &'a str },
-    Ssh { ip: &'a str, msg: &'a str },
+    Apache(Request<'a>),
+    Nginx(Request<'a>),
+    /// Access log line without a method and target, e.g. a TLS handshake sent to
+    /// a plain HTTP port logged as `"\x16\x03\x01" 400` or a timeout as `"-" 408`
+    Malformed {
+        ip: IpAddr,
+        /// Request line as logged
+        request: &'a str,
+        status: Option<u16>,
+    },
+    Ssh {
+        ip: IpAddr,
+        msg: &'a str,
+    },
 }
 
 impl<'a> Log<'a> {
-    pub fn ip(&self) -> &str {
+    pub fn ip(&self) -> IpAddr {
         match self {
-            Self::Apache { ip, .. } => ip,
-            Self::Nginx { ip, .. } => ip,
-            Self::Ssh { ip, .. } => ip,
+            Self::Apache(request) => request.ip,
+            Self::Nginx(request) => request.ip,
+            Self::Malformed { ip, .. } => *ip,
+            Self::Ssh { ip, .. } => *ip,
         }
     }
 
     pub fn message(&self) -> &str {
         match self {
-            Self::Apache { path, .. } => path,
-            Self::Nginx { path, .. } => path,
+            Self::Apache(request) => request.target,
+            Self::Nginx(request) => request.target,
+            Self::Malformed { request, .. } => request,
             Self::Ssh { msg, .. } => msg,
         }
     }
 }
 
-fn parse_access(line: &str) -> Option<(&str, &str)> {
-    // IP address is the first whitespace-separated token
-    let mut parts = line.splitn(2, ' ');
-    let ip = parts.next()?;
-    let rest = parts.next()?;
+// Quoted field at the start of the text and the text after it, `\"` is escaped
+fn quoted(text: &str) -> Option<(&str, &str)> {
+    let text = text.trim_start().strip_prefix('"')?;
+
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            '"' if !escaped => return Some((&text[..i], &text[i + 1..])),
+            '\\' => escaped = !escaped,
+            _ => escaped = false,
+        }
+    }
+    None
+}
 
-    // Find the first quoted request
-    let first_quote = rest.find('"')?;
-    let after_first = &rest[first_quote + 1..];
+// Whitespace-separated word at the start of the text and the text after it
+fn word(text: &str) -> Option<(&str, &str)> {
+    let text = text.trim_start();
+    match text.split_once(' ') {
+        Some((word, rest)) => Some((word, rest)),
+        None => (!text.is_empty()).then_some((text, "")),
+    }
+}
 
-    let second_quote = after_first.find('"')?;
-    let request = &after_first[..second_quote];
+// Combined log format: IP - user [date] "METHOD TARGET HTTP/X.Y" status bytes "referer" "user agent".
+// `log` wraps the request of the line, Malformed without a method and target
+fn parse_access<'a>(line: &'a str, log: fn(Request<'a>) -> Log<'a>) -> Option<Log<'a>> {
+    // IP address is the first whitespace-separated token
+    let (ip, rest) = line.split_once(' ')?;
+
+    // The request is the first quoted field, after the date
+    let (request, rest) = quoted(&rest[rest.find('"')?..])?;
+    let (status, rest) = word(rest).unwrap_or_default();
+    let status = status.parse().ok();
 
     // Request should be: METHOD PATH HTTP/X.Y
     let mut req_parts = request.split_whitespace();
 
-    let _ = req_parts.next()?; // GET/POST/CONNECT etc.
-    let path = req_parts.next()?;
+    let (Some(method), Some(target)) = (req_parts.next(), req_parts.next()) else {
+        return Some(Log::Malformed {
+            ip: parse_ip(ip)?,
+            request,
+            status,
+        });
+    };
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (target, None),
+    };
+
+    let (bytes, rest) = word(rest).unwrap_or_default();
+    let referer = quoted(rest);
+    let user_agent = referer.and_then(|(_, rest)| quoted(rest));
 
-    Some((ip, path))
+    Some(log(Request {
+        ip: parse_ip(ip)?,
+        method,
+        target,
+        path,
+        query,
+        status,
+        bytes: match bytes {
+            "-" => Some(0),
+            bytes => bytes.parse().ok(),
+        },
+        referer: referer.map(|(referer, _)| referer),
+        user_agent: user_agent.map(|(user_agent, _)| user_agent),
+    }))
 }
 
 pub fn parse_nginx(line: &str) -> Option<Log<'_>> {
-    let (ip, path) = parse_access(line)?;
-    Some(Log::Nginx { ip, path })
+    parse_access(line, Log::Nginx)
 }
 
 pub fn parse_apache(line: &str) -> Option<Log<'_>> {
-    let (ip, path) = parse_access(line)?;
-    Some(Log::Apache { ip, path })
+    parse_access(line, Log::Apache)
 }
 
 #[cfg(test)]
 mod tests_apache2_nginx {
-    use super::{Log, parse_apache};
+    use super::{Log, Request, parse_apache, parse_nginx};
+    use std::net::IpAddr;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
 
     #[test]
     fn test_parse_apache() {
         let cases = vec![
             (
                 r#"123.45.67.89 - - [24/Oct/2025:09:00:16 +0000] "GET / HTTP/1.1" 301 574 "-" "Mozilla/5.0""#,
-                Some(Log::Apache {
-                    ip: "123.45.67.89",
-                    path: "/",
-                }),
+                Some((ip("123.45.67.89"), "/")),
             ),
             (
                 r#"98.76.54.32 - - [24/Oct/2025:09:20:49 +0000] "GET /e.php HTTP/1.1" 403 439 "-" "curl/8.14.1""#,
-                Some(Log::Apache {
-                    ip: "98.76.54.32",
-                    path: "/e.php",
-                }),
+                Some((ip("98.76.54.32"), "/e.php")),
             ),
             (
                 r#"111.222.333.44 - - [24/Oct/2025:10:17:16 +0000] "\x16\x03\x01" 400 483 "-" "-""#,
                 None,
             ),
+            (
+                r#"111.222.33.44 - - [24/Oct/2025:10:17:16 +0000] "\x16\x03\x01" 400 483 "-" "-""#,
+                Some((ip("111.222.33.44"), r"\x16\x03\x01")),
+            ),
             (
                 r#"55.66.77.88 - - [24/Oct/2025:10:17:34 +0000] "-" 408 0 "-" "-""#,
-                None,
+                Some((ip("55.66.77.88"), "-")),
             ),
             (
                 r#"123.123.123.123 - - [24/Oct/2025:10:09:35 +0000] "CONNECT api.my-ip.io:443 HTTP/1.1" 301 518 "-" "Go-http-client/1.1""#,
-                Some(Log::Apache {
-                    ip: "123.123.123.123",
-                    path: "api.my-ip.io:443",
-                }),
+                Some((ip("123.123.123.123"), "api.my-ip.io:443")),
             ),
             (
                 r#"123.123.123.123 - - [25/Oct/2025:00:29:11 +0000] "GET /mail/.env.db HTTP/1.1" 301 536 "-" "Opera/8.02 (Windows NT 5.1; U; ru)""#,
-                Some(Log::Apache {
-                    ip: "123.123.123.123",
-                    path: "/mail/.env.db",
-                }),
+                Some((ip("123.123.123.123"), "/mail/.env.db")),
             ),
             (
                 r#"123.123.123.123 - - [25/Oct/2025:11:10:28 +0000] "GET /db/phpmyadmin/index.php?lang=en HTTP/1.1" 301 574 "-" "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/137.0.0.0 Safari/537.36""#,
-                Some(Log::Apache {
-                    ip: "123.123.123.123",
-                    path: "/db/phpmyadmin/index.php?lang=en",
-                }),
+                Some((ip("123.123.123.123"), "/db/phpmyadmin/index.php?lang=en")),
+            ),
+            (
+                r#"::ffff:1.2.3.4 - - [25/Oct/2025:11:10:28 +0000] "GET /.env HTTP/1.1" 404 196 "-" "-""#,
+                Some((ip("1.2.3.4"), "/.env")),
+            ),
+            (
+                r#"2001:db8::1 - - [25/Oct/2025:11:10:28 +0000] "GET /.env HTTP/1.1" 404 196 "-" "-""#,
+                Some((ip("2001:db8::1"), "/.env")),
+            ),
+            (
+                r#"crawler.example.com - - [25/Oct/2025:11:10:28 +0000] "GET /.env HTTP/1.1" 404 196 "-" "-""#,
+                None,
             ),
         ];
 
         for (line, expected) in cases {
             let parsed = parse_apache(line);
-            assert_eq!(parsed, expected, "Line that failed: {}", line);
+            assert_eq!(
+                parsed.as_ref().map(|log| (log.ip(), log.message())),
+                expected,
+                "Line that failed: {}",
+                line
+            );
         }
     }
-}
 
-pub fn parse_ssh(line: &str) -> Option<Log<'_>> {
-    if !(line.contains("sshd")
-        && (line.contains("Invalid user") || line.contains("Failed password")))
-    {
-        return None;
+    #[test]
+    fn test_parse_request_fields() {
+        let cases = [
+            (
+                r#"1.2.3.4 - - [25/Oct/2025:11:10:28 +0000] "GET /search?q=a?b HTTP/1.1" 200 574 "https://example.com/" "Mozilla/5.0 (X11; Linux x86_64)""#,
+                Request {
+                    ip: ip("1.2.3.4"),
+                    method: "GET",
+                    target: "/search?q=a?b",
+                    path: "/search",
+                    query: Some("q=a?b"),
+                    status: Some(200),
+                    bytes: Some(574),
+                    referer: Some("https://example.com/"),
+                    user_agent: Some("Mozilla/5.0 (X11; Linux x86_64)"),
+                },
+            ),
+            (
+                r#"1.2.3.4 - - [25/Oct/2025:11:10:28 +0000] "PROPFIND / HTTP/1.1" 405 - "-" "say \"hi\"""#,
+                Request {
+                    ip: ip("1.2.3.4"),
+                    method: "PROPFIND",
+                    target: "/",
+                    path: "/",
+                    query: None,
+                    status: Some(405),
+                    bytes: Some(0),
+                    referer: Some("-"),
+                    user_agent: Some(r#"say \"hi\""#),
+                },
+            ),
+            // Common log format
+            (
+                r#"1.2.3.4 - - [25/Oct/2025:11:10:28 +0000] "POST /login HTTP/1.1" 401 17"#,
+                Request {
+                    ip: ip("1.2.3.4"),
+                    method: "POST",
+                    target: "/login",
+                    path: "/login",
+                    query: None,
+                    status: Some(401),
+                    bytes: Some(17),
+                    referer: None,
+                    user_agent: None,
+                },
+            ),
+        ];
+
+        for (line, expected) in cases {
+            assert_eq!(
+                parse_apache(line),
+                Some(Log::Apache(expected)),
+                "Line that failed: {line}"
+            );
+        }
     }
 
-    let from_pos = line.find(" from ")?;
-    // Everything after " from "
-    let after = &line[from_pos + 6..];
+    #[test]
+    fn test_parse_malformed() {
+        let cases = [
+            (
+                r#"1.2.3.4 - - [24/Oct/2025:10:17:16 +0000] "\x16\x03\x01\x00\xf7\x01" 400 483 "-" "-""#,
+                r"\x16\x03\x01\x00\xf7\x01",
+                Some(400),
+            ),
+            (
+                r#"1.2.3.4 - - [24/Oct/2025:10:17:34 +0000] "-" 408 0 "-" "-""#,
+                "-",
+                Some(408),
+            ),
+            (
+                r#"1.2.3.4 - - [24/Oct/2025:10:17:34 +0000] "" 400 0"#,
+                "",
+                Some(400),
+            ),
+            (
+                r#"1.2.3.4 - - [24/Oct/2025:10:17:34 +0000] "SSH-2.0-Go" 400 157 "-" "-""#,
+                "SSH-2.0-Go",
+                Some(400),
+            ),
+        ];
+
+        for (line, request, status) in cases {
+            assert_eq!(
+                parse_nginx(line),
+                Some(Log::Malformed {
+                    ip: ip("1.2.3.4"),
+                    request,
+                    status
+                }),
+                "Line that failed: {line}"
+            );
+        }
+        // Lines without a request line are not access log lines
+        assert_eq!(
+            parse_nginx("1.2.3.4 - - [24/Oct/2025:10:17:34 +0000] 400"),
+            None
+        );
+    }
+}
 
-    // The IP address is the first "word" before the next space
-    let ip_end = after.find(' ')?;
-    let ip = &after[..ip_end];
+/// Any sshd line with a client address, the message is matched against the ssh rules
+pub fn parse_ssh(line: &str) -> Option<Log<'_>> {
+    // `sshd[1]: ` or `sshd-session[1]: ` with OpenSSH 9.8+
+    let after_sshd = &line[line.find("sshd")?..];
+    let msg = &after_sshd[after_sshd.find(": ")? + 2..];
+
+    // `message repeated 2 times: [ Failed password for ...]`
+    let msg = match msg.strip_prefix("message repeated ") {
+        Some(repeated) => repeated
+            .split_once(" times: [")?
+            .1
+            .trim_start()
+            .strip_suffix(']')?,
+        None => msg,
+    };
 
     Some(Log::Ssh {
-        ip,
-        msg: "Potentially malicious attempt",
+        ip: parse_ip(ssh_client(msg)?)?,
+        msg,
     })
 }
 
+// Client address of the known messages, read from a fixed position since user names
+// and the disconnect text of clients can contain `from <ip> port <n>` too
+fn ssh_client(msg: &str) -> Option<&str> {
+    // `Unable to negotiate with <ip> port <n>: ...`, `Received disconnect from <ip> port <n>:...`
+    for prefix in ["Unable to negotiate with ", "Received disconnect from "] {
+        if let Some(rest) = msg.strip_prefix(prefix) {
+            let (ip, rest) = rest.split_once(' ')?;
+            return rest.starts_with("port ").then_some(ip);
+        }
+    }
+
+    // `Invalid user <user> from <ip> port <n>`,
+    // `Failed password for [invalid user ]<user> from <ip> port <n> ssh2`
+    let rest = if msg.starts_with("Invalid user ") {
+        msg
+    } else if msg.starts_with("Failed password for ") {
+        msg.strip_suffix(" ssh2")?
+    } else {
+        return None;
+    };
+    let (rest, port) = rest.rsplit_once(" port ")?;
+    port.parse::<u16>().ok()?;
+    let (_, ip) = rest.rsplit_once(" from ")?;
+    Some(ip)
+}
+
 #[cfg(test)]
 mod tests_ssh {
-    use super::{Log, parse_ssh};
+    use super::{Log, parse_ssh, take_invalid_ips};
+    use std::net::IpAddr;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
 
     #[test]
     fn test_parse_ssh_lines() {
@@ -145,24 +390,71 @@ mod tests_ssh {
             (
                 "2026-01-01T19:05:04.778851+00:00 rob sshd[1]: Invalid user sdfrob from 127.0.0.1 port 42",
                 Some(Log::Ssh {
-                    ip: "127.0.0.1",
-                    msg: "Potentially malicious attempt",
+                    ip: ip("127.0.0.1"),
+                    msg: "Invalid user sdfrob from 127.0.0.1 port 42",
                 }),
             ),
             (
                 "2026-01-01T19:05:47.383708+00:00 rob sshd[1]: Failed password for invalid user sdfrob from 127.0.0.1 port 42 ssh2",
                 Some(Log::Ssh {
-                    ip: "127.0.0.1",
-                    msg: "Potentially malicious attempt",
+                    ip: ip("127.0.0.1"),
+                    msg: "Failed password for invalid user sdfrob from 127.0.0.1 port 42 ssh2",
                 }),
             ),
             (
                 "2026-01-01T19:06:09.199880+00:00 rob sshd[1]: Failed password for rob from 127.0.0.1 port 42 ssh2",
                 Some(Log::Ssh {
-                    ip: "127.0.0.1",
-                    msg: "Potentially malicious attempt",
+                    ip: ip("127.0.0.1"),
+                    msg: "Failed password for rob from 127.0.0.1 port 42 ssh2",
+                }),
+            ),
+            (
+                "2026-01-01T19:06:09.199880+00:00 rob sshd[1]: Failed password for rob from ::ffff:10.0.0.1 port 42 ssh2",
+                Some(Log::Ssh {
+                    ip: ip("10.0.0.1"),
+                    msg: "Failed password for rob from ::ffff:10.0.0.1 port 42 ssh2",
                 }),
             ),
+            (
+                "2026-01-01T19:06:09.199880+00:00 rob sshd-session[1]: Invalid user port from 10.0.0.2 port 42",
+                Some(Log::Ssh {
+                    ip: ip("10.0.0.2"),
+                    msg: "Invalid user port from 10.0.0.2 port 42",
+                }),
+            ),
+            (
+                "2026-01-01T19:06:09.199880+00:00 rob sshd[1]: Unable to negotiate with 2001:db8::1 port 42: no matching key exchange method found.",
+                Some(Log::Ssh {
+                    ip: ip("2001:db8::1"),
+                    msg: "Unable to negotiate with 2001:db8::1 port 42: no matching key exchange method found.",
+                }),
+            ),
+            (
+                "2026-01-01T19:06:09.199880+00:00 rob sshd[1]: message repeated 2 times: [ Failed password for root from 1.2.3.4 port 42 ssh2]",
+                Some(Log::Ssh {
+                    ip: ip("1.2.3.4"),
+                    msg: "Failed password for root from 1.2.3.4 port 42 ssh2",
+                }),
+            ),
+            // The text of the client is never read as its address
+            (
+                "2026-01-01T19:06:09.199880+00:00 rob sshd[1]: Received disconnect from 1.2.3.4 port 51234:11: Failed password for root from 9.9.9.9 port 22 ssh2 [preauth]",
+                Some(Log::Ssh {
+                    ip: ip("1.2.3.4"),
+                    msg: "Received disconnect from 1.2.3.4 port 51234:11: Failed password for root from 9.9.9.9 port 22 ssh2 [preauth]",
+                }),
+            ),
+            (
+                "2026-01-01T19:06:09.199880+00:00 rob sshd[1]: Invalid user x from 9.9.9.9 port 1 from 1.2.3.4 port 42",
+                Some(Log::Ssh {
+                    ip: ip("1.2.3.4"),
+                    msg: "Invalid user x from 9.9.9.9 port 1 from 1.2.3.4 port 42",
+                }),
+            ),
+            (
+                "2026-01-01T19:06:09.199880+00:00 rob sshd[1]: Connection closed by invalid user admin 1.2.3.4 port 42 [preauth]",
+                None,
+            ),
             (
                 "2026-01-01T00:00:00.000000+00:00 rob CRON[1]: pam_unix(cron:session): session closed for user root",
                 None,
@@ -182,4 +474,13 @@ mod tests_ssh {
             assert_eq!(parsed, expected, "Line that failed: {}", line);
         }
     }
+
+    #[test]
+    fn test_invalid_ip_is_counted() {
+        let line = "2026-01-01T19:06:09.199880+00:00 rob sshd[1]: Invalid user rob from host.example.com port 42";
+
+        take_invalid_ips();
+        assert_eq!(parse_ssh(line), None);
+        assert!(take_invalid_ips() >= 1);
+    }
 }
@@ -21,13 +21,20 @@ impl TailReader {
         let inode = metadata.ino();
 
         Ok(Self {
-            path: path,
+            path,
             file: Mutex::new(file),
             position: Mutex::new(pos),
             inode: Mutex::new(inode),
         })
     }
 
+    /// Reads the whole file, for log files created while running
+    pub fn from_start(path: PathBuf) -> std::io::Result<Self> {
+        let reader = Self::new(path)?;
+        *reader.position.lock().unwrap() = 0;
+        Ok(reader)
+    }
+
     pub fn read_new_lines(&self) -> Vec<String> {
         let mut lines = Vec::new();
         let path = &*self.path;
@@ -55,11 +62,7 @@ impl TailReader {
         if file.seek(SeekFrom::Start(*pos)).is_ok() {
             let reader = BufReader::new(&*file);
 
-            for line in reader.lines() {
-                if let Ok(line) = line {
-                    lines.push(line);
-                }
-            }
+            lines.extend(lossy_lines(reader));
 
             *pos = file.seek(SeekFrom::End(0)).unwrap_or(*pos);
         }
@@ -67,3 +70,56 @@ impl TailReader {
         lines
     }
 }
+
+/// Lines up to EOF or a read error, invalid UTF-8 is replaced instead of ending the read
+pub fn lossy_lines(mut reader: impl BufRead) -> impl Iterator<Item = String> {
+    let mut buf = Vec::new();
+    std::iter::from_fn(move || {
+        buf.clear();
+        match reader.read_until(b'\n', &mut buf) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => {
+                let line = buf.strip_suffix(b"\n").unwrap_or(&buf);
+                let line = line.strip_suffix(b"\r").unwrap_or(line);
+                Some(String::from_utf8_lossy(line).into_owned())
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TailReader, lossy_lines};
+    use std::{
+        env::temp_dir,
+        fs::{OpenOptions, remove_file, write},
+        io::Write,
+    };
+
+    #[test]
+    fn test_from_start() {
+        let path = temp_dir().join(format!("ubuntu-guard-reader-{}.log", std::process::id()));
+        write(&path, "first\nsecond\n").unwrap();
+
+        let tail = TailReader::new(path.clone()).unwrap();
+        let whole = TailReader::from_start(path.clone()).unwrap();
+        assert!(tail.read_new_lines().is_empty());
+        assert_eq!(whole.read_new_lines(), ["first", "second"]);
+
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        writeln!(file, "third").unwrap();
+        assert_eq!(tail.read_new_lines(), ["third"]);
+        assert_eq!(whole.read_new_lines(), ["third"]);
+
+        file.write_all(b"\xff bad\nfourth\n").unwrap();
+        assert_eq!(tail.read_new_lines(), ["\u{fffd} bad", "fourth"]);
+
+        remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_utf8_line() {
+        let lines: Vec<_> = lossy_lines(&b"a\n\xff\xfe bad\nc\r\nd\n"[..]).collect();
+        assert_eq!(lines, ["a", "\u{fffd}\u{fffd} bad", "c", "d"]);
+    }
+}
@@ -1,164 +1,666 @@
-// LEGACY REGEX
-// static BAD_PATH_REGEX: LazyLock<RegexSet> = LazyLock::new(|| {
-//     RegexSet::new(&[
-//         r"(?i)/\.env(\.[A-Za-z0-9_-]+)?(\b|/|\?)",
-//         r"(?i)^/(\.config)\b",
-//         r"(?i)^/config\.json\b",
-//         r"(?i)^/(?:config|web)\.xml\b",
-//         r"(?i)^/actuator(/|$)",
-//         r"(?i)^/boaform(/|$)",
-//         r"(?i)^/zabbix(/|$)",
-//         r"(?i)^/druid(/|$)",
-//         r"(?i)^/jasperserver(/|$)",
-//         r"(?i)^/partymgr(/|$)",
-//         r"(?i)^/admin(/|$)",
-//         r"(?i)^/developmentserver(/|$)",
-//         r"(?i)^/phpmyadmin(/|$)",
-//         r"(?i)^/wordpress(/|$)",
-//         r"(?i)^/telescope(/|$)",
-//         r"(?i)^/\+CSCOE\+(/|$)",
-//         r"(?i)^/console(/|$)",
-//         r"(?i)^/geoserver(/|$)",
-//         r"(?i)^/service/api-docs(/|$)",
-//         r"(?i)^/goform(/|$)",
-//         r"(?i)^/_profiler(/|$)",
-//         r"(?i)^/_ignition(/|$)",
-//         r"(?i)^/cgi-bin(/|$)",
-//         r"(?i)^/wp-[^/\s]+",
-//         r"(?i)^/solr",
-//         r"(?i)/\.git(|ignore|config|modules)(\.[A-Za-z0-9_-]+)?(\b|/|$)",
-//         r"(?i)^/shell\?",
-//         r"(?i)^/query\?",
-//         r"(?i)\?XDEBUG_SESSION_START=",
-//         r"(?i)\.(php[0-9]*|env|zip|tar|gz|tgz|rar|bak|jar|old|save|example|db|sqlite3?|ini|yaml|yml|cfg|conf|rsp|aspx|asp[0-9]*|jsp[0-9]*|cgi|xml)(\b|/|\?|$)",
-//         r"(?i)^/(?:vendor|storage|config|resources|public)/.*\.(env|php|ini|yaml|yml|cfg|conf)(\b|/|\?|$)",
-//         r"(?i)^/(?:env|php|cgi|shell|config|backup|backups)(/|$)",
-//         r"\.\./",
-//         r"(?i)^/\.(aws|docker|envrc|envs|local|production|remote|ssh|vscode)(/|$)",
-//         r"(?i)^/\.(aws|docker|ssh|vscode)/.*\.json(\b|/|\?|$)",
-//         r"(?i)^/(?:sftp-config\.json|sftp\.json)$",
-//         r"(?i)^/.+\b/sftp-config\.json$",
-//         r"(?i)^/.+\b/sftp\.json$",
-//         r"(?i)^/(private|prevlaravel|src/config|src)/.*\.(json|env|yaml|yml|ini|cfg|conf)(\b|/|\?|$)",
-//         r"(?i)^/tsconfig(\.[A-Za-z0-9_-]+)?\.json(\b|/|\?|$)",
-//     ])
-//     .unwrap()
-// });
-
-const BAD_PREFIXES: &[&str] = &[
-    "/actuator",
-    "/boaform",
-    "/zabbix",
-    "/druid",
-    "/jasperserver",
-    "/partymgr",
-    "/admin",
-    "/developmentserver",
-    "/phpmyadmin",
-    "/wordpress",
-    "/telescope",
-    "/+cscoe+",
-    "/console",
-    "/geoserver",
-    "/service/api-docs",
-    "/goform",
-    "/_profiler",
-    "/_ignition",
-    "/cgi-bin",
-    "/solr",
-    "/vendor",
-    "/storage",
-    "/config",
-    "/resources",
-    "/public",
-    "/env",
-    "/php",
-    "/cgi",
-    "/shell",
-    "/backup",
-    "/backups",
-    "/private",
-    "/prevlaravel",
-    "/src",
-];
-
-const BAD_EXT: &[&str] = &[
-    ".php", ".php0", ".env", ".zip", ".tar", ".gz", ".tgz", ".rar", ".bak", ".jar", ".old",
-    ".save", ".example", ".db", ".sqlite", ".sqlite3", ".ini", ".yaml", ".yml", ".cfg", ".conf",
-    ".rsp", ".aspx", ".asp", ".jsp", ".cgi", ".xml",
-];
-
-const ROOT_DOT_FILES: &[&str] = &[
-    "/.env",
-    "/.config",
-    "/.aws",
-    "/.docker",
-    "/.ssh",
-    "/.vscode",
-    "/.git",
-    "/.local",
-    "/.production",
-    "/.remote",
-];
-
-pub fn is_bad_path(path: &str) -> bool {
-    let p = path.to_ascii_lowercase();
-
-    // Traversal
-    if p.contains("../") {
-        return true;
-    }
-
-    // WordPress patterns
-    if p.starts_with("/wp-") {
-        return true;
-    }
-
-    // Suspicious query flags
-    if p.contains("?xdebug_session_start=") {
-        return true;
-    }
-
-    // Sensitive dot files
-    for file in ROOT_DOT_FILES {
-        if p.starts_with(file) {
-            return true;
+use crate::{glob, parse_logs::Request};
+#[cfg(feature = "regex")]
+use regex::{Regex, RegexBuilder};
+use std::{
+    fmt,
+    fs::read_to_string,
+    path::Path,
+    sync::{LazyLock, RwLock},
+};
+
+/// Weight of an event which is banned immediately, whatever the threshold
+pub const INSTANT_BAN: u64 = u64::MAX;
+
+/// Rules shipped with the binary, used unless a rules file replaces them
+const DEFAULT_RULES: &str = include_str!("../rules/default.rules");
+
+/// Rules used by `request_match` and `ssh_match`, replaced when the rules file is reloaded
+static RULES: LazyLock<RwLock<RuleSet>> = LazyLock::new(|| RwLock::new(RuleSet::default()));
+
+/// Part of a log line a rule is matched against
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Field {
+    /// Path of an HTTP request, the default
+    Path,
+    /// Query string of an HTTP request, after the `?`
+    Query,
+    /// HTTP method, e.g. `GET`
+    Method,
+    /// HTTP response status, e.g. `404`
+    Status,
+    /// Size of the HTTP response body
+    Bytes,
+    Referer,
+    /// User agent of an HTTP request
+    UserAgent,
+    /// Request line of a malformed HTTP request as logged, e.g. `\x16\x03\x01` or `-`
+    Request,
+    /// Message of an sshd line, e.g. `Invalid user admin from 1.2.3.4 port 22`
+    Ssh,
+}
+
+impl Field {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "path" => Some(Field::Path),
+            "query" => Some(Field::Query),
+            "method" => Some(Field::Method),
+            "status" => Some(Field::Status),
+            "bytes" => Some(Field::Bytes),
+            "referer" => Some(Field::Referer),
+            "ua" => Some(Field::UserAgent),
+            "request" => Some(Field::Request),
+            "ssh" => Some(Field::Ssh),
+            _ => None,
         }
     }
+}
 
-    // Sensitive root files
-    if p == "/config.json" || p == "/sftp-config.json" || p == "/sftp.json" {
-        return true;
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Field::Path => "path",
+            Field::Query => "query",
+            Field::Method => "method",
+            Field::Status => "status",
+            Field::Bytes => "bytes",
+            Field::Referer => "referer",
+            Field::UserAgent => "ua",
+            Field::Request => "request",
+            Field::Ssh => "ssh",
+        };
+        f.write_str(name)
     }
+}
+
+#[derive(Clone, Debug)]
+pub enum RuleKind {
+    Prefix,
+    Suffix,
+    Contains,
+    Exact,
+    /// The value ends with the pattern or has it before a `?`, e.g. an encoded one
+    Extension,
+    /// `*` is any run of characters and `?` any one character
+    Glob,
+    /// The value is a number greater than the pattern
+    Gt(u64),
+    /// The value is a number less than the pattern
+    Lt(u64),
+    /// The value is none of the comma-separated pattern, e.g. `get,head,post`
+    NoneOf(Vec<String>),
+    /// Case-insensitive, matches anywhere unless anchored
+    #[cfg(feature = "regex")]
+    Regex(Regex),
+}
 
-    // tsconfig.*.json
-    if p.starts_with("/tsconfig") && p.ends_with(".json") {
-        return true;
+impl RuleKind {
+    fn matches(&self, pattern: &str, value: &str) -> bool {
+        match self {
+            RuleKind::Prefix => value.starts_with(pattern),
+            RuleKind::Suffix => value.ends_with(pattern),
+            RuleKind::Contains => value.contains(pattern),
+            RuleKind::Exact => value == pattern,
+            RuleKind::Extension => {
+                value.ends_with(pattern) || value.contains(&format!("{pattern}?"))
+            }
+            RuleKind::Glob => glob::matches(pattern, value),
+            RuleKind::Gt(limit) => value.parse::<u64>().is_ok_and(|value| value > *limit),
+            RuleKind::Lt(limit) => value.parse::<u64>().is_ok_and(|value| value < *limit),
+            RuleKind::NoneOf(values) => !values.iter().any(|other| other == value),
+            #[cfg(feature = "regex")]
+            RuleKind::Regex(regex) => regex.is_match(value),
+        }
     }
 
-    for prefix in BAD_PREFIXES {
-        if p.starts_with(prefix) {
+    fn is_regex(&self) -> bool {
+        #[cfg(feature = "regex")]
+        if let RuleKind::Regex(_) = self {
             return true;
         }
+        false
+    }
+}
+
+impl fmt::Display for RuleKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            RuleKind::Prefix => "prefix",
+            RuleKind::Suffix => "suffix",
+            RuleKind::Contains => "contains",
+            RuleKind::Exact => "exact",
+            RuleKind::Extension => "extension",
+            RuleKind::Glob => "glob",
+            RuleKind::Gt(_) => "gt",
+            RuleKind::Lt(_) => "lt",
+            RuleKind::NoneOf(_) => "none-of",
+            #[cfg(feature = "regex")]
+            RuleKind::Regex(_) => "regex",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Check of one field of a rule
+#[derive(Clone, Debug)]
+pub struct Condition {
+    pub field: Field,
+    pub kind: RuleKind,
+    /// Lowercase unless a regex, values are lowercased before matching
+    pub pattern: String,
+}
+
+impl Condition {
+    /// `[field] <kind> <pattern>` and the tokens after it
+    fn from_tokens(tokens: &[String]) -> Result<(Self, &[String]), String> {
+        let (field, tokens) = tokens
+            .split_first()
+            .and_then(|(field, rest)| Some((Field::parse(field)?, rest)))
+            .unwrap_or((Field::Path, tokens));
+        let [kind, pattern, rest @ ..] = tokens else {
+            return Err("missing pattern".to_string());
+        };
+
+        if pattern.is_empty() {
+            return Err("empty pattern".to_string());
+        }
+        let number = || {
+            pattern
+                .parse()
+                .map_err(|_| format!("invalid number '{pattern}'"))
+        };
+        let kind = match kind.as_str() {
+            "prefix" => RuleKind::Prefix,
+            "suffix" => RuleKind::Suffix,
+            "contains" => RuleKind::Contains,
+            "exact" => RuleKind::Exact,
+            "extension" => RuleKind::Extension,
+            "glob" => RuleKind::Glob,
+            "gt" => RuleKind::Gt(number()?),
+            "lt" => RuleKind::Lt(number()?),
+            "none-of" => RuleKind::NoneOf(
+                pattern
+                    .split(',')
+                    .map(|value| value.trim().to_ascii_lowercase())
+                    .collect(),
+            ),
+            #[cfg(feature = "regex")]
+            "regex" => RuleKind::Regex(
+                RegexBuilder::new(pattern)
+                    .case_insensitive(true)
+                    .build()
+                    .map_err(|e| format!("invalid regex '{pattern}': {e}"))?,
+            ),
+            #[cfg(not(feature = "regex"))]
+            "regex" => return Err("regex rules require the regex feature".to_string()),
+            other => return Err(format!("unknown rule kind '{other}'")),
+        };
+
+        let condition = Self {
+            field,
+            pattern: match kind.is_regex() {
+                true => pattern.clone(),
+                false => pattern.to_ascii_lowercase(),
+            },
+            kind,
+        };
+        Ok((condition, rest))
     }
 
-    // Shell/query execution attempts
-    if p.starts_with("/shell?") || p.starts_with("/query?") {
-        return true;
+    /// Whether any value of the field matches, values must be lowercase
+    fn matches(&self, values: &[(Field, String)]) -> bool {
+        values
+            .iter()
+            .any(|(field, value)| *field == self.field && self.kind.matches(&self.pattern, value))
     }
+}
 
-    for ext in BAD_EXT {
-        if p.ends_with(ext) || p.contains(&format!("{ext}?")) {
-            return true;
+/// `[field] <kind> <pattern>` as in the rules file, the field is omitted for paths
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.field != Field::Path {
+            write!(f, "{} ", self.field)?;
+        }
+        if self.pattern == "&"
+            || self
+                .pattern
+                .contains(|c: char| c.is_whitespace() || c == '#')
+        {
+            write!(f, "{} \"{}\"", self.kind, self.pattern)
+        } else {
+            write!(f, "{} {}", self.kind, self.pattern)
+        }
+    }
+}
+
+/// Conditions which must all match, e.g. `status exact 401 & prefix /login`
+#[derive(Clone, Debug)]
+pub struct Rule {
+    pub conditions: Vec<Condition>,
+    /// 0 for ignore rules
+    pub weight: u64,
+}
+
+impl Rule {
+    /// Conditions separated by `&`, then the weight unless an ignore rule
+    fn from_groups(groups: &[Vec<String>], ignore: bool) -> Result<Self, String> {
+        let mut conditions = Vec::new();
+        let mut weight = None;
+
+        for (i, group) in groups.iter().enumerate() {
+            let (condition, rest) = Condition::from_tokens(group)?;
+            conditions.push(condition);
+
+            // Only the last condition may be followed by the weight
+            let is_last = i + 1 == groups.len();
+            match rest {
+                [] => {}
+                [last] if is_last => weight = Some(last.as_str()),
+                [_, extra, ..] if is_last => return Err(format!("unexpected '{extra}'")),
+                [extra, ..] => return Err(format!("unexpected '{extra}'")),
+            }
+        }
+
+        let weight = match weight {
+            None if ignore => 0,
+            None => 1,
+            Some(_) if ignore => return Err("ignore rules have no weight".to_string()),
+            Some("instant") => INSTANT_BAN,
+            Some(weight) => weight
+                .parse()
+                .ok()
+                .filter(|&weight| weight > 0)
+                .ok_or_else(|| format!("invalid weight '{weight}'"))?,
+        };
+
+        Ok(Self { conditions, weight })
+    }
+
+    /// `[field] <kind> <pattern> [& ...]` of an ignore rule, e.g. `prefix /public/`
+    pub fn parse_ignore(rule: &str) -> Result<Self, String> {
+        let groups = tokenize(rule)?;
+        if groups.is_empty() {
+            return Err("missing pattern".to_string());
+        }
+        Self::from_groups(&groups, true)
+    }
+
+    fn matches(&self, values: &[(Field, String)]) -> bool {
+        self.conditions
+            .iter()
+            .all(|condition| condition.matches(values))
+    }
+
+    fn is_regex(&self) -> bool {
+        self.conditions
+            .iter()
+            .any(|condition| condition.kind.is_regex())
+    }
+
+    pub fn fields(&self) -> impl Iterator<Item = Field> + '_ {
+        self.conditions.iter().map(|condition| condition.field)
+    }
+}
+
+/// Conditions as in the rules file, without the weight
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, condition) in self.conditions.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" & ")?;
+            }
+            write!(f, "{condition}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Rule matched by a bad request
+#[derive(Clone, Debug, PartialEq)]
+pub struct RuleMatch {
+    /// Conditions of the rule, e.g. `prefix /admin`
+    pub rule: String,
+    pub weight: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct RuleSet {
+    /// String rules first, regexes are slower and only evaluated when they could
+    /// outweigh the best match so far
+    rules: Vec<Rule>,
+    /// Values which are never bad, checked before the rules
+    ignores: Vec<Rule>,
+}
+
+impl RuleSet {
+    /// Parses rules, one per line: `[field] <kind> <pattern> [& ...] [weight] [# comment]`
+    /// or `ignore [field] <kind> <pattern> [& ...]`. Patterns with spaces, `#` or `&`
+    /// are quoted, the weight is a number or `instant`
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut rules = Vec::new();
+        let mut ignores = Vec::new();
+
+        for (i, line) in text.lines().enumerate() {
+            let line_error = |e: String| format!("line {}: {e}", i + 1);
+
+            let mut groups = tokenize(line).map_err(line_error)?;
+            match groups.first_mut() {
+                None => {}
+                Some(first) if first.first().is_some_and(|token| token == "ignore") => {
+                    first.remove(0);
+                    ignores.push(Rule::from_groups(&groups, true).map_err(line_error)?);
+                }
+                Some(_) => rules.push(Rule::from_groups(&groups, false).map_err(line_error)?),
+            }
+        }
+
+        let mut rules = Self { rules, ignores };
+        rules.sort();
+        Ok(rules)
+    }
+
+    /// Rules of the file, after the built-in ones if `with_defaults`
+    pub fn load(path: &Path, with_defaults: bool) -> Result<Self, String> {
+        let text = read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+        let rules = Self::parse(&text).map_err(|e| format!("{}: {e}", path.display()))?;
+
+        if !with_defaults {
+            return Ok(rules);
+        }
+        let mut all = Self::default();
+        all.rules.extend(rules.rules);
+        all.ignores.extend(rules.ignores);
+        all.sort();
+        Ok(all)
+    }
+
+    // Stable, rules of the same kind keep their order
+    fn sort(&mut self) {
+        self.rules.sort_by_key(Rule::is_regex);
+        self.ignores.sort_by_key(Rule::is_regex);
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len() + self.ignores.len()
+    }
+
+    /// Highest weighted rule matching the request, the first one of equal weights.
+    /// None if the request is not bad. Paths and query strings are matched as logged
+    /// and normalised. Rules on a field are skipped if an ignore rule on it matches,
+    /// of the set or the given ones, ignore rules only see normalised values
+    pub fn request_match(&self, request: &Request, ignores: &[Rule]) -> Option<RuleMatch> {
+        let mut values = vec![
+            (Field::Path, request.path.to_ascii_lowercase()),
+            (
+                Field::Path,
+                normalize_path(request.path).to_ascii_lowercase(),
+            ),
+        ];
+        if let Some(query) = request.query {
+            values.push((Field::Query, query.to_ascii_lowercase()));
+            values.push((Field::Query, normalize_query(query).to_ascii_lowercase()));
+        }
+        values.push((Field::Method, request.method.to_ascii_lowercase()));
+        if let Some(status) = request.status {
+            values.push((Field::Status, status.to_string()));
+        }
+        if let Some(bytes) = request.bytes {
+            values.push((Field::Bytes, bytes.to_string()));
+        }
+        if let Some(referer) = request.referer {
+            values.push((Field::Referer, referer.to_ascii_lowercase()));
+        }
+        match request.user_agent {
+            // An empty user agent is as good as none
+            Some("") => values.push((Field::UserAgent, "-".to_string())),
+            Some(user_agent) => values.push((Field::UserAgent, user_agent.to_ascii_lowercase())),
+            None => {}
+        }
+        values.dedup();
+
+        self.find(&values, ignores)
+    }
+
+    /// Highest weighted rule matching a malformed request, like `request_match`
+    pub fn malformed_match(
+        &self,
+        request: &str,
+        status: Option<u16>,
+        ignores: &[Rule],
+    ) -> Option<RuleMatch> {
+        let mut values = vec![(Field::Request, request.to_ascii_lowercase())];
+        if let Some(status) = status {
+            values.push((Field::Status, status.to_string()));
+        }
+        self.find(&values, ignores)
+    }
+
+    /// Highest weighted rule matching the message of an sshd line, like `request_match`
+    pub fn ssh_match(&self, msg: &str, ignores: &[Rule]) -> Option<RuleMatch> {
+        self.find(&[(Field::Ssh, msg.to_ascii_lowercase())], ignores)
+    }
+
+    // Lowercase values, the last one of a field is its normalised value.
+    // Paths and queries with a traversal are never ignored, so an ignored prefix
+    // cannot hide `/admin/../../etc/passwd`
+    fn find(&self, values: &[(Field, String)], ignores: &[Rule]) -> Option<RuleMatch> {
+        let normalized: Vec<(Field, String)> = values
+            .iter()
+            .enumerate()
+            .filter(|(i, (field, _))| values[i + 1..].iter().all(|(other, _)| other != field))
+            .map(|(_, value)| value.clone())
+            .filter(|(field, value)| match field {
+                Field::Path => !value.split('/').any(|segment| segment == ".."),
+                Field::Query => !value.contains("../"),
+                _ => true,
+            })
+            .collect();
+        let ignored: Vec<Field> = self
+            .ignores
+            .iter()
+            .chain(ignores)
+            .filter(|rule| rule.matches(&normalized))
+            .flat_map(Rule::fields)
+            .collect();
+
+        let mut best: Option<&Rule> = None;
+        for rule in &self.rules {
+            if best.is_some_and(|best| best.weight >= rule.weight)
+                || rule.fields().any(|field| ignored.contains(&field))
+            {
+                continue;
+            }
+            if rule.matches(values) {
+                best = Some(rule);
+            }
+        }
+
+        best.map(|rule| RuleMatch {
+            rule: rule.to_string(),
+            weight: rule.weight,
+        })
+    }
+}
+
+impl Default for RuleSet {
+    fn default() -> Self {
+        Self::parse(DEFAULT_RULES).expect("Built-in rules are valid")
+    }
+}
+
+// Rounds of percent-decoding, enough for double and triple encoding
+const DECODE_ROUNDS: usize = 3;
+
+/// Percent-decodes the path, including double encoding like `%252e`, and collapses
+/// duplicate slashes and `.` segments. `..` segments are kept
+pub fn normalize_path(path: &str) -> String {
+    let path = percent_decode(path);
+    let mut normalized: String = path
+        .split('/')
+        .enumerate()
+        .filter(|(i, segment)| *i == 0 || !matches!(*segment, "" | "."))
+        .map(|(_, segment)| segment)
+        .collect::<Vec<_>>()
+        .join("/");
+    if path.starts_with('/') && !normalized.starts_with('/') {
+        normalized.insert(0, '/');
+    }
+    if (path.ends_with('/') || path.ends_with("/.")) && !normalized.ends_with('/') {
+        normalized.push('/');
+    }
+    normalized
+}
+
+/// Percent-decodes the query string like the path, `+` is a space
+pub fn normalize_query(query: &str) -> String {
+    percent_decode(&query.replace('+', " "))
+}
+
+fn percent_decode(s: &str) -> String {
+    let mut decoded = s.to_string();
+
+    for _ in 0..DECODE_ROUNDS {
+        if !decoded.contains('%') {
+            break;
+        }
+
+        let bytes = decoded.as_bytes();
+        let mut out = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            match bytes.get(i..i + 3) {
+                Some([b'%', high, low]) if high.is_ascii_hexdigit() && low.is_ascii_hexdigit() => {
+                    out.push(hex_value(*high) << 4 | hex_value(*low));
+                    i += 3;
+                }
+                _ => {
+                    out.push(bytes[i]);
+                    i += 1;
+                }
+            }
+        }
+
+        let round = String::from_utf8_lossy(&out).into_owned();
+        if round == decoded {
+            break;
+        }
+        decoded = round;
+    }
+
+    decoded
+}
+
+fn hex_value(digit: u8) -> u8 {
+    match digit {
+        b'0'..=b'9' => digit - b'0',
+        b'a'..=b'f' => digit - b'a' + 10,
+        _ => digit - b'A' + 10,
+    }
+}
+
+/// Splits a line at whitespace up to a `#` comment into groups separated by `&`,
+/// quoted tokens may contain all three. No groups for an empty line
+fn tokenize(line: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut groups = vec![Vec::new()];
+    let mut chars = line.trim().chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '#' {
+            break;
+        } else if c == '"' {
+            chars.next();
+            let mut token = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some(c) => token.push(c),
+                    None => return Err("unterminated quote".to_string()),
+                }
+            }
+            groups.last_mut().unwrap().push(token);
+        } else {
+            let mut token = String::new();
+            while let Some(&c) = chars.peek()
+                && !c.is_whitespace()
+            {
+                token.push(c);
+                chars.next();
+            }
+            if token == "&" {
+                groups.push(Vec::new());
+            } else {
+                groups.last_mut().unwrap().push(token);
+            }
         }
     }
 
-    false
+    if groups.len() == 1 && groups[0].is_empty() {
+        groups.clear();
+    }
+    Ok(groups)
+}
+
+/// Replaces the rules used by `request_match` and `ssh_match`
+pub fn set_rules(rules: RuleSet) {
+    *RULES.write().unwrap() = rules;
+}
+
+/// Highest weighted rule of the current rules matching the request, see `RuleSet::request_match`
+pub fn request_match(request: &Request, ignores: &[Rule]) -> Option<RuleMatch> {
+    RULES.read().unwrap().request_match(request, ignores)
+}
+
+/// Highest weighted rule of the current rules matching the malformed request
+pub fn malformed_match(request: &str, status: Option<u16>, ignores: &[Rule]) -> Option<RuleMatch> {
+    RULES
+        .read()
+        .unwrap()
+        .malformed_match(request, status, ignores)
+}
+
+/// Highest weighted rule of the current rules matching the sshd message
+pub fn ssh_match(msg: &str, ignores: &[Rule]) -> Option<RuleMatch> {
+    RULES.read().unwrap().ssh_match(msg, ignores)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::is_bad_path;
+    use super::{
+        INSTANT_BAN, Rule, RuleMatch, RuleSet, malformed_match, normalize_path, normalize_query,
+        request_match, ssh_match,
+    };
+    use crate::parse_logs::{Log, Request, parse_nginx, parse_ssh};
+    use std::net::Ipv4Addr;
+
+    // GET of the target, split at the first `?` like the access log parser
+    fn request(target: &str) -> Request<'_> {
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (target, None),
+        };
+        Request {
+            ip: Ipv4Addr::LOCALHOST.into(),
+            method: "GET",
+            target,
+            path,
+            query,
+            status: None,
+            bytes: None,
+            referer: None,
+            user_agent: None,
+        }
+    }
+
+    fn target_match(rules: &RuleSet, target: &str, ignores: &[Rule]) -> Option<RuleMatch> {
+        rules.request_match(&request(target), ignores)
+    }
+
+    // With the current rules
+    fn default_match(target: &str) -> Option<RuleMatch> {
+        request_match(&request(target), &[])
+    }
 
     #[test]
     fn test_bad_paths() {
@@ -241,9 +743,17 @@ mod tests {
             "/admin",
             "/developmentserver/metadatauploader",
             "/+CSCOE+/logon_forms.js",
+            // Encoded or padded forms
+            "/%2e%2e/%2e%2e/etc/passwd",
+            "/%252e%252e/etc/passwd",
+            "/.%65nv",
+            "/%2E%65NV",
+            "//wp-login.php",
+            "/./.git/config",
+            "/static/..%2f..%2fetc/passwd",
         ];
         for p in bad_paths {
-            assert!(is_bad_path(p), "Should match {p}");
+            assert!(default_match(p).is_some(), "Should match {p}");
         }
     }
 
@@ -257,7 +767,591 @@ mod tests {
             "/foo.phpbiba",
         ];
         for p in good_paths {
-            assert!(!is_bad_path(p), "Should not match {p}");
+            assert_eq!(default_match(p), None, "Should not match {p}");
+        }
+    }
+
+    #[test]
+    fn test_path_rules() {
+        let cases = [
+            ("/admin", "prefix /admin", 1),
+            ("/backup.zip", "extension .zip", 2),
+            ("/wp-login.php", "prefix /wp-", 2),
+            ("/.git/config", "prefix /.git", 3),
+            ("/tsconfig.app.json", "glob /tsconfig*.json", 3),
+            // Highest weight of all matching rules
+            ("/admin/.env", "extension .env", 2),
+            (
+                "/index.php?view=../../etc/passwd",
+                "query contains ../",
+                INSTANT_BAN,
+            ),
+            ("/shell?cmd=id", "query contains cmd=", INSTANT_BAN),
+            ("/shell", "exact /shell", 3),
+        ];
+
+        for (path, rule, weight) in cases {
+            let matched = default_match(path).unwrap();
+            assert_eq!(matched.rule, rule, "Rule of {path}");
+            assert_eq!(matched.weight, weight, "Weight of {path}");
+        }
+    }
+
+    #[test]
+    fn test_parse_rules() {
+        let rules = RuleSet::parse(
+            r##"
+            # Scanners
+            prefix /Secret 4 # case is ignored
+            suffix .swp
+            exact "/my file.txt" instant
+            contains "#frag"
+            "##,
+        )
+        .unwrap();
+        assert_eq!(rules.len(), 4);
+
+        let cases = [
+            ("/secret/key", Some(("prefix /secret", 4))),
+            ("/index.html.SWP", Some(("suffix .swp", 1))),
+            (
+                "/my file.txt",
+                Some(("exact \"/my file.txt\"", INSTANT_BAN)),
+            ),
+            ("/page#frag", Some(("contains \"#frag\"", 1))),
+            ("/my file.txt.bak", None),
+            ("/admin", None),
+        ];
+        for (path, expected) in cases {
+            let matched = target_match(&rules, path, &[]);
+            assert_eq!(
+                matched.map(|m| (m.rule, m.weight)),
+                expected.map(|(rule, weight)| (rule.to_string(), weight)),
+                "Rule of {path}"
+            );
+        }
+
+        let errors = [
+            ("prefix", "line 1: missing pattern"),
+            ("regexp ^/a", "line 1: unknown rule kind 'regexp'"),
+            ("ssh", "line 1: missing pattern"),
+            ("\nprefix /a 0", "line 2: invalid weight '0'"),
+            ("prefix /a heavy", "line 1: invalid weight 'heavy'"),
+            ("prefix /a 1 2", "line 1: unexpected '2'"),
+            ("exact \"/a", "line 1: unterminated quote"),
+            ("exact \"\"", "line 1: empty pattern"),
+            ("ignore prefix /a 2", "line 1: ignore rules have no weight"),
+            ("ignore", "line 1: missing pattern"),
+        ];
+        for (text, expected) in errors {
+            assert_eq!(RuleSet::parse(text).err().unwrap(), expected, "{text:?}");
+        }
+    }
+
+    #[test]
+    fn test_ignore_rules() {
+        let rules = RuleSet::parse(
+            "prefix /admin\nextension .xml 2\ncontains ../ instant\nignore exact /API/config.xml",
+        )
+        .unwrap();
+        let source = [
+            Rule::parse_ignore("prefix /admin/").unwrap(),
+            Rule::parse_ignore("extension .xml").unwrap(),
+        ];
+
+        assert_eq!(target_match(&rules, "/api/config.xml", &[]), None);
+        assert!(target_match(&rules, "/api/other.xml", &[]).is_some());
+        assert!(target_match(&rules, "/admin/users", &[]).is_some());
+
+        // Ignore rules are checked before every other rule
+        for path in ["/admin/users", "/feed.xml?page=2"] {
+            assert_eq!(
+                target_match(&rules, path, &source),
+                None,
+                "Should ignore {path}"
+            );
+        }
+        assert!(target_match(&rules, "/administrator", &source).is_some());
+
+        // Traversals are never ignored
+        for path in ["/admin/../etc/passwd", "/admin/%2e%2e/%2e%2e/.env"] {
+            assert_eq!(
+                target_match(&rules, path, &source).map(|m| m.weight),
+                Some(INSTANT_BAN),
+                "Should catch {path}"
+            );
+        }
+        let ignore_all = [Rule::parse_ignore("prefix /").unwrap()];
+        assert_eq!(
+            target_match(&rules, "/static/..%2f..%2fetc/passwd", &ignore_all)
+                .unwrap()
+                .rule,
+            "contains ../"
+        );
+        let ignore_query = [Rule::parse_ignore("query prefix file=").unwrap()];
+        assert_eq!(
+            target_match(
+                &RuleSet::default(),
+                "/?file=..%2f..%2fetc/passwd",
+                &ignore_query
+            )
+            .unwrap()
+            .rule,
+            "query contains ../"
+        );
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        let cases = [
+            ("/index.html", "/index.html"),
+            ("/%2e%2e/%2e%2e/etc/passwd", "/../../etc/passwd"),
+            ("/%252e%252e%252fetc", "/../etc"),
+            ("/%25252e", "/"),
+            // Decoding stops after three rounds
+            ("/%2525252e", "/%2e"),
+            ("/.%65nv", "/.env"),
+            ("//wp-login.php", "/wp-login.php"),
+            ("/./.git//config", "/.git/config"),
+            ("/admin/./", "/admin/"),
+            ("/a/../b", "/a/../b"),
+            ("/search%3fq=1", "/search?q=1"),
+            ("/%zz%4", "/%zz%4"),
+            ("/%+f", "/%+f"),
+            ("/caf%C3%A9", "/café"),
+            ("api.my-ip.io:443", "api.my-ip.io:443"),
+        ];
+
+        for (target, expected) in cases {
+            assert_eq!(normalize_path(target), expected, "Normalised {target}");
+        }
+    }
+
+    #[test]
+    fn test_normalize_query() {
+        let cases = [
+            ("q=php", "q=php"),
+            ("q=1+UNION+SELECT+1", "q=1 UNION SELECT 1"),
+            ("q=%3Cscript%3E", "q=<script>"),
+            ("q=%2B1", "q=+1"),
+            ("file=..%252f", "file=../"),
+        ];
+
+        for (query, expected) in cases {
+            assert_eq!(normalize_query(query), expected, "Normalised {query}");
         }
     }
+
+    #[test]
+    fn test_query_rules() {
+        let cases = [
+            (
+                "/?XDEBUG_SESSION_START=phpstorm",
+                Some("query contains xdebug_session_start="),
+            ),
+            (
+                "/search?q=1+UNION+SELECT+password",
+                Some("query contains \"union select\""),
+            ),
+            ("/?q=%3Cscript%3Ealert(1)", Some("query contains <script")),
+            (
+                "/?x=${jndi:ldap://1.2.3.4/a}",
+                Some("query contains ${jndi:"),
+            ),
+            (
+                "/page?file=..%2f..%2fetc%2fpasswd",
+                Some("query contains ../"),
+            ),
+            // Extensions and prefixes are matched against the path only
+            ("/download?file=report.php", None),
+            ("/search?q=/admin", None),
+            ("/search?q=php", None),
+            ("/info.php?cmd=id", Some("query contains cmd=")),
+        ];
+
+        for (target, expected) in cases {
+            assert_eq!(
+                default_match(target).map(|matched| matched.rule),
+                expected.map(str::to_string),
+                "Rule of {target}"
+            );
+        }
+
+        let ignores = [Rule::parse_ignore("query prefix cmd=ls").unwrap()];
+        let rules = RuleSet::default();
+        assert_eq!(target_match(&rules, "/tools?cmd=ls", &ignores), None);
+        // Path rules still apply
+        assert_eq!(
+            target_match(&rules, "/tools.php?cmd=ls", &ignores)
+                .unwrap()
+                .rule,
+            "extension .php"
+        );
+    }
+
+    #[test]
+    fn test_normalized_rules() {
+        let rules =
+            RuleSet::parse("prefix /.git 3\nextension .php 2\nignore exact /api/config.json")
+                .unwrap();
+
+        // The raw form is matched too, the heavier rule wins
+        assert_eq!(
+            target_match(&rules, "/./.git/%2e%2e/x.php", &[])
+                .unwrap()
+                .rule,
+            "prefix /.git"
+        );
+        assert_eq!(
+            target_match(&rules, "/x.ph%70", &[]).unwrap().rule,
+            "extension .php"
+        );
+        // Ignore rules match the normalised form
+        assert_eq!(target_match(&rules, "/api//config.json", &[]), None);
+        assert_eq!(
+            target_match(
+                &rules,
+                "//x.php",
+                &[Rule::parse_ignore("exact /x.php").unwrap()]
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_field_rules() {
+        let rules = RuleSet::parse(
+            r#"
+            status exact 401 & prefix /login 2
+            method none-of get,head,post 3
+            bytes gt 1000000 & extension .sql instant
+            referer contains "evil.example"
+            ua prefix curl/ & status lt 400
+            ignore method exact options & path exact /
+            "#,
+        )
+        .unwrap();
+
+        let login = Request {
+            status: Some(401),
+            ..request("/login?next=/")
+        };
+        let dump = Request {
+            bytes: Some(5_000_000),
+            ..request("/db.sql")
+        };
+        let cases = [
+            (login, Some(("status exact 401 & prefix /login", 2))),
+            (
+                Request {
+                    status: Some(200),
+                    ..request("/login")
+                },
+                None,
+            ),
+            (
+                Request {
+                    method: "PROPFIND",
+                    ..request("/")
+                },
+                Some(("method none-of get,head,post", 3)),
+            ),
+            (
+                dump,
+                Some(("bytes gt 1000000 & extension .sql", INSTANT_BAN)),
+            ),
+            (
+                Request {
+                    bytes: Some(0),
+                    ..request("/db.sql")
+                },
+                None,
+            ),
+            (
+                Request {
+                    referer: Some("https://EVIL.example/"),
+                    ..request("/")
+                },
+                Some(("referer contains evil.example", 1)),
+            ),
+            (
+                Request {
+                    user_agent: Some("curl/8.5.0"),
+                    status: Some(200),
+                    ..request("/")
+                },
+                Some(("ua prefix curl/ & status lt 400", 1)),
+            ),
+            (
+                Request {
+                    user_agent: Some("curl/8.5.0"),
+                    ..request("/")
+                },
+                None,
+            ),
+            // Every field of the ignore rule is skipped
+            (
+                Request {
+                    method: "OPTIONS",
+                    ..request("/")
+                },
+                None,
+            ),
+            (
+                Request {
+                    method: "OPTIONS",
+                    ..request("/index.html")
+                },
+                Some(("method none-of get,head,post", 3)),
+            ),
+        ];
+        for (request, expected) in cases {
+            assert_eq!(
+                rules
+                    .request_match(&request, &[])
+                    .map(|m| (m.rule, m.weight)),
+                expected.map(|(rule, weight)| (rule.to_string(), weight)),
+                "Rule of {request:?}"
+            );
+        }
+
+        let connect = Request {
+            method: "CONNECT",
+            ..request("api.my-ip.io:443")
+        };
+        assert_eq!(
+            request_match(&connect, &[]).unwrap().rule,
+            "method exact connect"
+        );
+
+        let ignores = [Rule::parse_ignore("status exact 401 & path exact /login").unwrap()];
+        let login = Request {
+            status: Some(401),
+            ..request("/login")
+        };
+        assert_eq!(rules.request_match(&login, &ignores), None);
+
+        let errors = [
+            ("status gt 4xx", "line 1: invalid number '4xx'"),
+            ("prefix /a 2 & status exact 404", "line 1: unexpected '2'"),
+            ("prefix /a &", "line 1: missing pattern"),
+            ("& prefix /a", "line 1: missing pattern"),
+        ];
+        for (text, expected) in errors {
+            assert_eq!(RuleSet::parse(text).err().unwrap(), expected, "{text:?}");
+        }
+    }
+
+    #[test]
+    fn test_user_agent_rules() {
+        let cases = [
+            ("Mozilla/5.0 zgrab/0.x", Some(("ua contains zgrab", 3))),
+            (
+                "masscan/1.3 (https://github.com/robertdavidgraham/masscan)",
+                Some(("ua contains masscan", 3)),
+            ),
+            (
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64) Chrome/74.0.3729.169 Nuclei - Open-source project (github.com/projectdiscovery/nuclei)",
+                Some(("ua contains nuclei", 3)),
+            ),
+            (
+                "sqlmap/1.8#stable (https://sqlmap.org)",
+                Some(("ua contains sqlmap", INSTANT_BAN)),
+            ),
+            ("Go-http-client/1.1", Some(("ua prefix go-http-client", 1))),
+            // Requests without a user agent only count when they miss
+            ("-", None),
+            ("", None),
+            (
+                "Mozilla/5.0 (X11; Linux x86_64; rv:128.0) Gecko/20100101 Firefox/128.0",
+                None,
+            ),
+            ("curl/8.5.0", None),
+        ];
+
+        for (user_agent, expected) in cases {
+            let line = format!(
+                r#"1.2.3.4 - - [10/Oct/2024:13:55:36 +0000] "GET / HTTP/1.1" 200 612 "-" "{user_agent}""#
+            );
+            let Some(Log::Nginx(request)) = parse_nginx(&line) else {
+                panic!("Should parse {line}");
+            };
+            assert_eq!(
+                request_match(&request, &[]).map(|m| (m.rule, m.weight)),
+                expected.map(|(rule, weight)| (rule.to_string(), weight)),
+                "Rule of {user_agent:?}"
+            );
+        }
+
+        for user_agent in ["-", ""] {
+            let line = format!(
+                r#"1.2.3.4 - - [10/Oct/2024:13:55:36 +0000] "GET /backup HTTP/1.1" 404 153 "-" "{user_agent}""#
+            );
+            let Some(Log::Nginx(request)) = parse_nginx(&line) else {
+                panic!("Should parse {line}");
+            };
+            assert_eq!(
+                request_match(&request, &[]).unwrap().rule,
+                "ua exact - & status exact 404",
+                "Rule of {user_agent:?}"
+            );
+        }
+
+        // Common log format lines have no user agent
+        let line = r#"1.2.3.4 - - [10/Oct/2024:13:55:36 +0000] "GET / HTTP/1.1" 200 612"#;
+        let Some(Log::Nginx(request)) = parse_nginx(line) else {
+            panic!("Should parse {line}");
+        };
+        assert_eq!(request_match(&request, &[]), None);
+    }
+
+    #[test]
+    fn test_malformed_rules() {
+        let cases = [
+            (
+                r#""\x16\x03\x01\x00\xf7\x01" 400 483"#,
+                Some((r"request prefix \x16\x03", 3)),
+            ),
+            (r#""SSH-2.0-Go" 400 157"#, Some(("request prefix ssh-", 3))),
+            // Idle connections of browsers and load balancers
+            (r#""-" 408 0"#, None),
+            (r#""" 400 0"#, None),
+        ];
+
+        for (request, expected) in cases {
+            let line = format!(r#"1.2.3.4 - - [10/Oct/2024:13:55:36 +0000] {request} "-" "-""#);
+            let Some(Log::Malformed {
+                request, status, ..
+            }) = parse_nginx(&line)
+            else {
+                panic!("Should parse {line}");
+            };
+            assert_eq!(
+                malformed_match(request, status, &[]).map(|m| (m.rule, m.weight)),
+                expected.map(|(rule, weight)| (rule.to_string(), weight)),
+                "Rule of {request:?}"
+            );
+        }
+
+        let rules =
+            RuleSet::parse("request glob * 2\nignore request exact - & status exact 408").unwrap();
+        assert_eq!(rules.malformed_match("-", Some(408), &[]), None);
+        assert!(rules.malformed_match("-", Some(400), &[]).is_some());
+        // Request rules only match malformed requests
+        assert_eq!(target_match(&rules, "/", &[]), None);
+    }
+
+    #[test]
+    fn test_ssh_rules() {
+        let cases = [
+            ("Invalid user admin from 1.2.3.4 port 22", true),
+            ("Failed password for root from 1.2.3.4 port 22 ssh2", true),
+            (
+                "message repeated 2 times: [ Failed password for root from 1.2.3.4 port 22 ssh2]",
+                true,
+            ),
+            (
+                "Connection closed by invalid user admin 1.2.3.4 port 22 [preauth]",
+                false,
+            ),
+            (
+                "Accepted publickey for rob from 1.2.3.4 port 22 ssh2",
+                false,
+            ),
+            // Disconnect text is chosen by the client
+            (
+                "Received disconnect from 1.2.3.4 port 51234:11: Failed password for root from 9.9.9.9 port 22 ssh2 [preauth]",
+                false,
+            ),
+        ];
+        for (msg, bad) in cases {
+            let line = format!("2026-01-01T00:00:00.000000+00:00 rob sshd[1]: {msg}");
+            let matched = match parse_ssh(&line) {
+                Some(Log::Ssh { msg, .. }) => ssh_match(msg, &[]),
+                _ => None,
+            };
+            assert_eq!(matched.is_some(), bad, "{msg}");
+        }
+        // Path rules only match paths
+        assert_eq!(
+            ssh_match("Invalid user /admin from 1.2.3.4 port 22", &[])
+                .unwrap()
+                .rule,
+            "ssh prefix \"invalid user\""
+        );
+
+        let rules =
+            RuleSet::parse("ssh contains \"no matching\" 2\nignore ssh contains \" 10.0.0.\"")
+                .unwrap();
+        assert_eq!(
+            rules.ssh_match(
+                "Unable to negotiate with 1.2.3.4 port 22: no matching host key type found",
+                &[]
+            ),
+            Some(RuleMatch {
+                rule: "ssh contains \"no matching\"".to_string(),
+                weight: 2
+            })
+        );
+        assert_eq!(
+            rules.ssh_match(
+                "Unable to negotiate with 10.0.0.1 port 22: no matching cipher",
+                &[]
+            ),
+            None
+        );
+        assert_eq!(target_match(&rules, "/no matching", &[]), None);
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn test_regex_rules() {
+        let rules = RuleSet::parse(
+            r#"
+            regex "^/\.env(\.\w+)?$" 3
+            prefix /.env 2
+            regex /(backup|dump)\.SQL 2
+            prefix /backup
+            ssh regex "^Invalid user \S+ from"
+            "#,
+        )
+        .unwrap();
+
+        let cases = [
+            // String rules are matched first, regexes only replace them when heavier
+            ("/.env.local", Some((r"regex ^/\.env(\.\w+)?$", 3))),
+            ("/.envrc/x", Some(("prefix /.env", 2))),
+            ("/backup.sql", Some((r"regex /(backup|dump)\.SQL", 2))),
+            ("/backup/dump.sql", Some((r"regex /(backup|dump)\.SQL", 2))),
+            ("/backup/", Some(("prefix /backup", 1))),
+            ("/index.html", None),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(
+                target_match(&rules, path, &[]).map(|m| (m.rule, m.weight)),
+                expected.map(|(rule, weight)| (rule.to_string(), weight)),
+                "Rule of {path}"
+            );
+        }
+        assert!(
+            rules
+                .ssh_match("Invalid user admin from 1.2.3.4 port 22", &[])
+                .is_some()
+        );
+
+        let ignore = [Rule::parse_ignore(r"regex ^/backup/\d+/").unwrap()];
+        assert_eq!(target_match(&rules, "/backup/2024/dump.sql", &ignore), None);
+
+        let error = RuleSet::parse("regex (").err().unwrap();
+        assert!(error.starts_with("line 1: invalid regex '('"), "{error}");
+    }
+
+    #[cfg(not(feature = "regex"))]
+    #[test]
+    fn test_regex_rules_disabled() {
+        assert_eq!(
+            RuleSet::parse("regex ^/a").err().unwrap(),
+            "line 1: regex rules require the regex feature"
+        );
+    }
 }
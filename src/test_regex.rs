@@ -1,13 +1,14 @@
-use std::{
-    fs::File,
-    io::{BufRead, BufReader},
-};
+use std::{fs::File, io::BufReader};
 
-use crate::{LogSource, parse_logs};
+use crate::{LogKind, LogSource, parse_logs, reader::lossy_lines};
 
-pub fn test(path: &str, print_matched: bool, print_missed: bool) {
-    let source = LogSource::from_path(path);
-    let file = File::open(path).expect(&format!("Failed to open {path}"));
+/// Kind is guessed from the path if not given
+pub fn test(path: &str, kind: Option<LogKind>, print_matched: bool, print_missed: bool) {
+    let mut source = LogSource::from_path(path);
+    if let Some(kind) = kind {
+        source.kind = kind;
+    }
+    let file = File::open(path).unwrap_or_else(|e| panic!("Failed to open {path}: {e}"));
     let reader = BufReader::new(file);
 
     println!("Testing regex for {path}\n");
@@ -17,46 +18,29 @@ pub fn test(path: &str, print_matched: bool, print_missed: bool) {
     let mut unmatched_lines = 0;
     let mut failed_parse_lines = 0;
 
-    for line in reader.lines().flatten() {
+    for line in lossy_lines(reader) {
         total_lines += 1;
-        let mut matched = false;
+        let mut matched = None;
         let mut parsed_ok = false;
 
         if let Some(parsed) = source.parse(&line) {
             parsed_ok = true;
-            match parsed {
-                parse_logs::Log::Apache { path, .. } => {
-                    if source.is_bad(path) {
-                        matched = true;
-                        matched_lines += 1;
-                    } else {
-                        unmatched_lines += 1;
-                    }
-                }
-                parse_logs::Log::Nginx { path, .. } => {
-                    if source.is_bad(path) {
-                        matched = true;
-                        matched_lines += 1;
-                    } else {
-                        unmatched_lines += 1;
-                    }
-                }
-                parse_logs::Log::Ssh { msg, .. } => {
-                    if source.is_bad(msg) {
-                        matched = true;
-                        matched_lines += 1;
-                    } else {
-                        unmatched_lines += 1;
-                    }
-                }
+            matched = source.detect(&parsed);
+
+            if matched.is_some() {
+                matched_lines += 1;
+            } else {
+                unmatched_lines += 1;
             }
         } else {
             failed_parse_lines += 1;
         }
 
-        if matched && print_matched {
-            println!("[MATCHED] {line}");
-        } else if !matched && parsed_ok && print_missed {
+        if let Some(matched) = &matched {
+            if print_matched {
+                println!("[MATCHED] {line} ({})", matched.rule);
+            }
+        } else if parsed_ok && print_missed {
             println!("[MISSED] {line}");
         } else if !parsed_ok && print_missed {
             println!("[FAILED TO PARSE] {line}");
@@ -66,4 +50,8 @@ pub fn test(path: &str, print_matched: bool, print_missed: bool) {
     println!(
         "\nProcessed {total_lines}, matched {matched_lines}, missed {unmatched_lines}, failed to parse {failed_parse_lines} lines."
     );
+    println!(
+        "{} of the lines that failed to parse had an invalid IP address.",
+        parse_logs::take_invalid_ips()
+    );
 }